@@ -1,15 +1,19 @@
 //! Module handling command line arguments.
 
 use std::env;
-use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::ffi::{OsStr, OsString};
 use std::iter::IntoIterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use clap::{self, AppSettings, Arg, ArgMatches, ArgSettings};
+use clap::{self, AppSettings, Arg, ArgMatches, SubCommand};
 use conv::TryFrom;
 use conv::errors::NoError;
+use isatty;
 
-use cargo::BuildMode;
+use runrs::{BuildMode, CargoOptions, ScriptOptions, Source};
+use runrs::{self, gc, options};
 
 
 /// Parse command line arguments and return matches' object.
@@ -24,15 +28,130 @@ pub fn parse() -> Result<Options, ArgsError> {
 pub fn parse_from_argv<I, T>(argv: I) -> Result<Options, ArgsError>
     where I: IntoIterator<Item=T>, T: Clone + Into<OsString>
 {
+    let argv = split_shebang_argv(argv.into_iter().map(Into::into).collect());
+    let shebang_argv = is_shebang_argv(&argv);
+    let argv = expand_toolchain_argv(argv);
+    let argv = hoist_subcommand_argv(argv);
+
     let parser = create_parser();
     let matches = try!(parser.get_matches_from_safe(argv));
 
     // Currently, clap can catch all errors related to arguments
     // so this always succeeds.
-    let options = Options::try_from(matches).unwrap();
+    let mut options = Options::try_from(matches).unwrap();
+    options.shebang_argv = shebang_argv;
     Ok(options)
 }
 
+/// Read the shebang line of given script file, if it has one.
+fn read_shebang<P: AsRef<Path>>(path: P) -> Option<String> {
+    let mut line = String::new();
+    let read = File::open(path).and_then(|f| BufReader::new(f).read_line(&mut line));
+    if read.is_ok() && line.starts_with("#!") && !line[2..].trim_start().starts_with('[') {
+        Some(line.trim_end().to_owned())
+    } else {
+        None
+    }
+}
+
+/// Split the first argument into separate flags if it came from a shebang line.
+///
+/// When a script with `#!/usr/bin/runrs --release -q` is executed directly,
+/// Linux passes everything that follows the interpreter path as a *single* argument,
+/// followed by the script's path.
+fn split_shebang_argv(mut argv: Vec<OsString>) -> Vec<OsString> {
+    let flags: Vec<OsString> = match (argv.get(1).and_then(|arg| arg.to_str()), argv.get(2)) {
+        (Some(arg), Some(script)) if arg.contains(char::is_whitespace) => {
+            // Other arguments may legitimately contain whitespace (like --features="a b"),
+            // so make sure it's actually the script's shebang that we're looking at.
+            let words: Vec<_> = arg.split_whitespace().collect();
+            match read_shebang(script) {
+                Some(ref shebang) if options::shebang_flags(shebang) == Some(words.clone()) => {
+                    words.into_iter().map(OsString::from).collect()
+                },
+                _ => return argv,
+            }
+        },
+        _ => return argv,
+    };
+    argv.splice(1..2, flags);
+    argv
+}
+
+/// Check whether given arguments start with the flags from the shebang of a script
+/// that comes right after them, i.e. whether the script has been executed directly.
+///
+/// This holds both for `#!/usr/bin/runrs --release` (once the argument is split)
+/// and for `#!/usr/bin/env -S runrs --release`.
+fn is_shebang_argv(argv: &[OsString]) -> bool {
+    // Flags can't be told apart from their values without knowing what the script's shebang is,
+    // so look for the first argument that is a file.
+    let script_index = match (1..argv.len()).find(|&i| Path::new(&argv[i]).is_file()) {
+        Some(i) => i,
+        None => return false,
+    };
+    let shebang = match read_shebang(&argv[script_index]) {
+        Some(shebang) => shebang,
+        None => return false,
+    };
+    options::shebang_flags(&shebang).is_some_and(|flags| {
+        flags.len() == script_index - 1
+            && flags.iter().zip(&argv[1..script_index]).all(|(f, a)| OsStr::new(f) == a)
+    })
+}
+
+/// Expand the `+toolchain` shorthand (like in `runrs +nightly script.rs`)
+/// into the --toolchain flag, if it's been given as the first argument.
+fn expand_toolchain_argv(mut argv: Vec<OsString>) -> Vec<OsString> {
+    let flag = match argv.get(1).and_then(|arg| arg.to_str()) {
+        Some(arg) if arg.starts_with('+') && arg.len() > 1 => format!("--toolchain={}", &arg[1..]),
+        _ => return argv,
+    };
+    argv[1] = flag.into();
+    argv
+}
+
+/// Move the name of a subcommand to the front of the arguments
+/// if it's only been preceded by flags (like in `runrs -v gc`).
+///
+/// The parser only recognizes subcommands before any other argument,
+/// as everything that follows the script path (which may be preceded by flags) is the script's.
+/// Flags like -v are then parsed as the subcommand's own.
+fn hoist_subcommand_argv(mut argv: Vec<OsString>) -> Vec<OsString> {
+    // Find the first positional argument, skipping the flags and their values.
+    let mut i = 1;
+    while let Some(arg) = argv.get(i).and_then(|arg| arg.to_str()) {
+        if arg == "--" || arg == STDIN_PATH || !arg.starts_with('-') {
+            break;
+        }
+        // With inline code, there is no script path, so anything else is the script's argument.
+        let takes_value = match arg.strip_prefix("--") {
+            Some(long) => {
+                let name = long.split('=').next().unwrap_or(long);
+                if name == OPT_EVAL {
+                    return argv;
+                }
+                options::VALUE_OPTS.contains(&name) && !long.contains('=')
+            },
+            // Short flags can be grouped, with the value (if any) following the last one.
+            None => match arg[1..].find(SHORT_VALUE_OPTS) {
+                Some(pos) if arg[1 + pos..].starts_with('e') => return argv,
+                Some(pos) => pos + 2 == arg.len(),
+                None => false,
+            },
+        };
+        i += if takes_value { 2 } else { 1 };
+    }
+
+    let is_subcommand = argv.get(i).and_then(|arg| arg.to_str())
+        .is_some_and(|arg| SUBCOMMANDS.contains(&arg));
+    if i > 1 && is_subcommand {
+        let subcommand = argv.remove(i);
+        argv.insert(1, subcommand);
+    }
+    argv
+}
+
 
 /// Structure that holds options received from the command line.
 /// This includes the script to run and its arguments.
@@ -43,38 +162,101 @@ pub struct Options {
     /// Corresponds to the number of times the -v flag has been passed.
     /// If -q has been used instead, this will be negative.
     pub verbosity: isize,
-    /// Path to the script to run.
-    // TODO: allow the script source to passed via stdin
-    pub script: PathBuf,
+    /// Where to read the script to run from
+    /// (or the script code itself, if it was given with -e).
+    pub script: Source,
     /// Arguments to the script.
     pub args: Vec<String>,
-    /// Build mode to use (debug vs. release).
-    pub build_mode: BuildMode,
+    /// Build mode to use (debug vs. release), if it's been given explicitly.
+    pub build_mode: Option<BuildMode>,
+    /// Options to pass to Cargo when building the script.
+    pub cargo: CargoOptions,
+    /// Subcommand to execute instead of running a script, if any.
+    pub subcommand: Option<Subcommand>,
+    /// Whether the arguments already include the flags from the script's shebang
+    /// (because the script has been executed directly).
+    pub shebang_argv: bool,
+}
+
+/// Subcommand of runrs, other than running a script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Subcommand {
+    /// Evict script crates that exceed given limits (`runrs gc`).
+    Gc {
+        /// Limits on the script crates to keep
+        /// (in addition to those from the configuration).
+        policy: gc::Policy,
+        /// Whether to only report the crates that would be evicted.
+        dry_run: bool,
+    },
+    /// List the scripts that have been built (`runrs list`).
+    List {
+        /// Whether to output JSON rather than a table.
+        json: bool,
+    },
+    /// Show what's known about given script's builds (`runrs info`).
+    Info {
+        /// The script to show the information about.
+        script: Source,
+    },
 }
 
 impl<'a> TryFrom<ArgMatches<'a>> for Options {
     type Err = NoError;
 
     fn try_from(matches: ArgMatches<'a>) -> Result<Self, Self::Err> {
-        let verbose_count = matches.occurrences_of(OPT_VERBOSE) as isize;
-        let quiet_count = matches.occurrences_of(OPT_QUIET) as isize;
-        let verbosity = verbose_count - quiet_count;
-
-        // Script file will be provided, but it doesn't have to have arguments.
-        // Assume an empty argument list if that's the case.
-        let script = matches.value_of(ARG_SCRIPT).unwrap();
-        let script_args = matches.values_of(ARG_SCRIPT_ARGV)
+        let build = ScriptOptions::from_matches(&matches);
+
+        // Script file can be omitted only if it's being piped through stdin,
+        // which can also be requested explicitly with "-".
+        // It doesn't have to have arguments, though; assume an empty list if that's the case.
+        let mut script_args: Vec<String> = matches.values_of(ARG_SCRIPT_ARGV)
             .map(|argv| argv.map(|v| v.to_owned()).collect())
             .unwrap_or_else(|| vec![]);
+        let script = match (matches.value_of(OPT_EVAL), matches.value_of(ARG_SCRIPT)) {
+            // With inline code, there is no script file,
+            // so what would be its path is actually the first script argument.
+            (Some(code), first_arg) => {
+                if let Some(arg) = first_arg {
+                    script_args.insert(0, arg.to_owned());
+                }
+                Source::Eval(code.to_owned())
+            },
+            (None, Some(STDIN_PATH)) | (None, None) => Source::Stdin,
+            (None, Some(path)) => Source::File(PathBuf::from(path)),
+        };
 
-        let build_mode = if matches.is_present(OPT_RELEASE) { BuildMode::Release }
-                         else                               { BuildMode::Debug };
+        let subcommand = match matches.subcommand() {
+            (SUBCMD_GC, Some(gc_matches)) => Some(Subcommand::Gc{
+                policy: gc::Policy{
+                    max_age: gc_matches.value_of(OPT_MAX_AGE)
+                        .map(|d| gc::days(d.parse().unwrap()).unwrap()),
+                    max_count: gc_matches.value_of(OPT_MAX_COUNT).map(|n| n.parse().unwrap()),
+                    max_size: gc_matches.value_of(OPT_MAX_SIZE)
+                        .map(|s| runrs::parse_size(s).unwrap()),
+                },
+                dry_run: gc_matches.is_present(OPT_DRY_RUN),
+            }),
+            (SUBCMD_LIST, Some(list_matches)) => Some(Subcommand::List{
+                json: list_matches.is_present(OPT_JSON),
+            }),
+            (SUBCMD_INFO, Some(info_matches)) => Some(Subcommand::Info{
+                script: match info_matches.value_of(ARG_SCRIPT) {
+                    Some(STDIN_PATH) | None => Source::Stdin,
+                    Some(path) => Source::File(PathBuf::from(path)),
+                },
+            }),
+            _ => None,
+        };
 
         Ok(Options{
-            verbosity: verbosity,
-            script: PathBuf::from(script),
+            verbosity: build.verbosity.unwrap_or(0),
+            script: script,
             args: script_args,
-            build_mode: build_mode,
+            build_mode: build.build_mode,
+            cargo: build.cargo,
+            subcommand: subcommand,
+            shebang_argv: false,
         })
     }
 }
@@ -96,42 +278,64 @@ const APP_DESC: &'static str = "Runner for Rust scripts";
 
 const ARG_SCRIPT: &'static str = "script";
 const ARG_SCRIPT_ARGV: &'static str = "argv";
-const OPT_VERBOSE: &'static str = "verbose";
-const OPT_QUIET: &'static str = "quiet";
-const OPT_RELEASE: &'static str = "release";
+const OPT_EVAL: &'static str = "eval";
+const SUBCMD_GC: &'static str = "gc";
+const OPT_MAX_AGE: &'static str = "max-age";
+const OPT_MAX_COUNT: &'static str = "max-count";
+const OPT_MAX_SIZE: &'static str = "max-size";
+const OPT_DRY_RUN: &'static str = "dry-run";
+const SUBCMD_LIST: &'static str = "list";
+const OPT_JSON: &'static str = "json";
+const SUBCMD_INFO: &'static str = "info";
+const SUBCOMMANDS: &'static [&'static str] = &[SUBCMD_GC, SUBCMD_LIST, SUBCMD_INFO];
+
+/// Short names of the (top-level) options which take a value.
+const SHORT_VALUE_OPTS: &'static [char] = &['e', 'j'];
+
+/// Special value of ARG_SCRIPT meaning that the script should be read from stdin.
+const STDIN_PATH: &'static str = "-";
 
 
 /// Create the argument parser.
 fn create_parser<'p>() -> Parser<'p> {
+    // If the script is being piped through stdin, its path can be omitted.
+    // Otherwise we require it, showing the usage help if no arguments were passed at all.
+    let stdin_tty = isatty::stdin_isatty();
+
     let mut parser = Parser::new(APP_NAME);
     if let Some(version) = option_env!("CARGO_PKG_VERSION") {
         parser = parser.version(version);
     }
+    if stdin_tty {
+        parser = parser.setting(AppSettings::ArgRequiredElseHelp);
+    }
+
+    let mut script_arg = Arg::with_name(ARG_SCRIPT)
+        .help("Rust source file to build & execute (or \"-\" to read it from stdin)")
+        .value_name("FILE");
+    if stdin_tty {
+        script_arg = script_arg.required_unless(OPT_EVAL);
+    }
     parser
         .about(APP_DESC)
 
-        .setting(AppSettings::ArgRequiredElseHelp)
         .setting(AppSettings::UnifiedHelpMessage)
         .setting(AppSettings::DeriveDisplayOrder)
         .setting(AppSettings::ColorNever)
 
-        // Verbosity flags.
-        .arg(Arg::with_name(OPT_VERBOSE)
-            .long("verbose").short("v")
-            .set(ArgSettings::Multiple)
-            .conflicts_with(OPT_QUIET)
-            .help("Increase logging verbosity"))
-        .arg(Arg::with_name(OPT_QUIET)
-            .long("quiet").short("q")
-            .set(ArgSettings::Multiple)
-            .conflicts_with(OPT_VERBOSE)
-            .help("Decrease logging verbosity"))
-
-        // Script to run and its arguments.
-        .arg(Arg::with_name(ARG_SCRIPT)
-            .required(true)
-            .help("Rust source file to build & execute")
-            .value_name("FILE"))
+        // Verbosity and build flags.
+        .args(&options::args())
+
+        // Script to run (or code to evaluate) and its arguments.
+        .arg(Arg::with_name(OPT_EVAL)
+            .long("eval").short("e")
+            .takes_value(true)
+            .number_of_values(1)
+            .help("Rust code to execute instead of a script file. \
+                   Wrapped in fn main() unless it defines one already; \
+                   the value of its final expression is printed")
+            .value_name("CODE"))
+        .arg(script_arg)
         // This argument spec is capturing everything after the script path,
         // allowing for the arguments to be passed to the script itself.
         .arg(Arg::with_name(ARG_SCRIPT_ARGV)
@@ -142,6 +346,168 @@ fn create_parser<'p>() -> Parser<'p> {
             .value_name("ARGS"))
         .setting(AppSettings::TrailingVarArg)
 
+        // Subcommands other than running a script.
+        // They're only recognized before the script path, so that scripts can take
+        // arguments like `gc` or `list` too.
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .subcommand(create_gc_parser())
+        .subcommand(create_list_parser())
+        .subcommand(create_info_parser())
+
         .help_short("H")
         .version_short("V")
 }
+
+/// Create the argument parser for the `gc` subcommand.
+fn create_gc_parser<'p>() -> Parser<'p> {
+    SubCommand::with_name(SUBCMD_GC)
+        .about("Evict the least recently used script crates that exceed given limits \
+                (or those from the [gc] section of the config file)")
+        .setting(AppSettings::UnifiedHelpMessage)
+        .setting(AppSettings::DeriveDisplayOrder)
+        .arg(Arg::with_name(OPT_MAX_AGE)
+            .long("max-age")
+            .takes_value(true)
+            .validator(|v| v.parse().ok().and_then(gc::days).map(|_| ())
+                .ok_or_else(|| format!("must be a number of days, got `{}`", v)))
+            .help("Evict crates that haven't been used for this many days")
+            .value_name("DAYS"))
+        .arg(Arg::with_name(OPT_MAX_COUNT)
+            .long("max-count")
+            .takes_value(true)
+            .validator(|v| v.parse::<usize>().map(|_| ())
+                .map_err(|_| format!("must be a number, got `{}`", v)))
+            .help("Keep at most this many crates")
+            .value_name("N"))
+        .arg(Arg::with_name(OPT_MAX_SIZE)
+            .long("max-size")
+            .takes_value(true)
+            .validator(|v| runrs::parse_size(&v).map(|_| ())
+                .ok_or_else(|| format!("must be a size like 500M or 2G, got `{}`", v)))
+            .help("Keep the crates (with their build artifacts) within this total size")
+            .value_name("SIZE"))
+        .arg(Arg::with_name(OPT_DRY_RUN)
+            .long("dry-run").short("n")
+            .help("Only show the crates that would be evicted"))
+}
+
+/// Create the argument parser for the `list` subcommand.
+fn create_list_parser<'p>() -> Parser<'p> {
+    SubCommand::with_name(SUBCMD_LIST)
+        .about("List the scripts that have been built, with some information about their builds")
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(Arg::with_name(OPT_JSON)
+            .long("json")
+            .help("Output JSON rather than a table"))
+}
+
+/// Create the argument parser for the `info` subcommand.
+fn create_info_parser<'p>() -> Parser<'p> {
+    // Like when running a script, its path can only be omitted if it's piped through stdin.
+    let stdin_tty = isatty::stdin_isatty();
+    SubCommand::with_name(SUBCMD_INFO)
+        .about("Show the builds of given script and whether it would be rebuilt on next run")
+        .setting(AppSettings::UnifiedHelpMessage)
+        .arg(Arg::with_name(ARG_SCRIPT)
+            .required(stdin_tty)
+            .help("Rust source file to show the builds of (or \"-\" to read it from stdin)")
+            .value_name("FILE"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{parse_from_argv, Subcommand};
+    use runrs::{BuildMode, Source};
+
+    #[test]
+    fn subcommand_name_after_script_is_script_arg() {
+        let opts = parse_from_argv(["runrs", "x.rs", "gc"]).unwrap();
+        assert_eq!(opts.subcommand, None);
+        assert_eq!(opts.script, Source::File("x.rs".into()));
+        assert_eq!(opts.args, vec!["gc".to_owned()]);
+    }
+
+    #[test]
+    fn subcommand_after_flags() {
+        let opts = parse_from_argv(["runrs", "-v", "gc", "--max-count", "1"]).unwrap();
+        assert!(matches!(opts.subcommand, Some(Subcommand::Gc{..})));
+        assert_eq!(opts.verbosity, 1);
+        let opts = parse_from_argv(["runrs", "-q", "list"]).unwrap();
+        assert_eq!(opts.subcommand, Some(Subcommand::List{json: false}));
+        assert_eq!(opts.verbosity, -1);
+
+        let opts = parse_from_argv(["runrs", "-v", "-j", "2", "x.rs", "gc"]).unwrap();
+        assert_eq!(opts.subcommand, None);
+        assert_eq!(opts.args, vec!["gc".to_owned()]);
+        let opts = parse_from_argv(["runrs", "-j", "2", "-e", "fn main() {}", "list"]).unwrap();
+        assert_eq!(opts.subcommand, None);
+        assert_eq!(opts.args, vec!["list".to_owned()]);
+    }
+
+    #[test]
+    fn global_flags_in_subcommand() {
+        let opts = parse_from_argv(["runrs", "gc", "-v", "--max-count", "1"]).unwrap();
+        assert!(matches!(opts.subcommand, Some(Subcommand::Gc{..})));
+        assert_eq!(opts.verbosity, 1);
+        let opts = parse_from_argv(["runrs", "list", "-qq"]).unwrap();
+        assert_eq!(opts.verbosity, -2);
+    }
+
+    #[test]
+    fn subcommand_before_script() {
+        let opts = parse_from_argv(["runrs", "list", "--json"]).unwrap();
+        assert_eq!(opts.subcommand, Some(Subcommand::List{json: true}));
+    }
+
+    #[test]
+    fn cargo_switches_can_be_turned_off() {
+        let opts = parse_from_argv(["runrs", "--offline=false", "-e", "fn main() {}"]).unwrap();
+        assert_eq!(opts.cargo.offline, Some(false));
+
+        let opts = parse_from_argv(["runrs", "--offline", "x.rs"]).unwrap();
+        assert_eq!(opts.cargo.offline, Some(true));
+        assert_eq!(opts.script, Source::File("x.rs".into()));
+    }
+
+    #[test]
+    fn whitespace_in_flag_values() {
+        let opts = parse_from_argv(["runrs", "--features=a b", "x.rs"]).unwrap();
+        assert_eq!(opts.cargo.features, vec!["a".to_owned(), "b".to_owned()]);
+        assert_eq!(opts.script, Source::File("x.rs".into()));
+        let opts = parse_from_argv(["runrs", "--cargo-arg=--config x", "x.rs"]).unwrap();
+        assert_eq!(opts.cargo.args, vec!["--config x".to_owned()]);
+        assert_eq!(opts.script, Source::File("x.rs".into()));
+    }
+
+    #[test]
+    fn shebang_flags_in_argv() {
+        let dir = env::temp_dir().join(format!("runrs-test-shebang-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("script.rs");
+        fs::write(&path, "#!/usr/bin/runrs --release --cargo-arg=-Zx\nfn main() {}\n").unwrap();
+        let path_str = path.to_str().unwrap();
+
+        // As passed by the kernel when the script is executed directly.
+        let opts = parse_from_argv(["/usr/bin/runrs", "--release --cargo-arg=-Zx", path_str])
+            .unwrap();
+        assert_eq!(opts.build_mode, Some(BuildMode::Release));
+        assert_eq!(opts.cargo.args, vec!["-Zx".to_owned()]);
+        assert!(opts.shebang_argv);
+        let opts = parse_from_argv(["runrs", "--release", "--cargo-arg=-Zx", path_str]).unwrap();
+        assert!(opts.shebang_argv);
+        let opts = parse_from_argv(["runrs", "--release", path_str]).unwrap();
+        assert!(!opts.shebang_argv);
+
+        // Not actually the shebang, so no splitting.
+        let result = parse_from_argv(["runrs", "--release -q", path_str]);
+        assert!(result.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
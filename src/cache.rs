@@ -0,0 +1,382 @@
+//! Module keeping track of the binaries built for scripts,
+//! so that scripts which haven't changed can be ran without involving Cargo at all.
+//!
+//! The index also keeps some metadata about the scripts and their builds,
+//! which is shown by `runrs list` and `runrs info`.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crypto::digest::Digest;
+use toml;
+
+use super::APP_DIR;
+use cargo::{Backend, BuildMode, CargoOptions};
+use manifest;
+use script::Script;
+use util;
+
+
+lazy_static! {
+    /// Directory with the index of binaries built for scripts.
+    ///
+    /// For every script (and build mode, and toolchain) there is a small TOML file here
+    /// with the SHA of the script code that the binary has been built from,
+    /// along with the script crate's directory and some metadata about the build.
+    pub static ref BINARY_INDEX_DIR: PathBuf = APP_DIR.join("binaries");
+}
+
+
+/// Find the binary that has been built for the script in its current form
+/// (and with given options), if any. The binary is then marked as used.
+pub fn lookup(script: &Script, mode: BuildMode, options: &CargoOptions) -> Option<PathBuf> {
+    let entry_file = entry_path(script, mode, options);
+    let mut entry = match Entry::read(&entry_file) {
+        Ok(entry) => entry,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            trace!("No binary recorded for the script"; "script" => script.source.to_string());
+            return None;
+        },
+        Err(err) => {
+            warn!("Malformed entry in the index of script binaries, ignoring it";
+                "path" => entry_file.display().to_string(), "error" => format!("{}", err));
+            return None;
+        },
+    };
+    if let Err(reason) = entry.check(script, options) {
+        trace!("Script binary can't be used";
+            "script" => script.source.to_string(), "reason" => reason.to_string());
+        return None;
+    }
+
+    debug!("Found up-to-date binary of the script";
+        "script" => script.source.to_string(), "binary" => entry.binary.display().to_string());
+
+    // Remember when the binary was used, so that `runrs gc` doesn't evict it too soon.
+    entry.last_used = util::unix_timestamp(SystemTime::now());
+    if let Err(err) = entry.write() {
+        warn!("Failed to update the entry in the index of script binaries";
+            "path" => entry_file.display().to_string(), "error" => format!("{}", err));
+    }
+    Some(entry.binary)
+}
+
+/// Record the binary that has just been built by given backend (which took `build_time`)
+/// for the script in its current form (and with given options).
+pub fn record(script: &Script, mode: BuildMode, options: &CargoOptions,
+              backend: &Backend, build_time: Duration) {
+    let binary = backend.binary_path(mode.clone());
+    let (mtime, binary_size) = match fs::metadata(&binary)
+            .and_then(|m| modification_time(&m).map(|mtime| (mtime, m.len()))) {
+        Ok(result) => result,
+        Err(err) => {
+            warn!("Failed to check the script binary, not recording it in the index";
+                "binary" => binary.display().to_string(), "error" => format!("{}", err));
+            return;
+        },
+    };
+
+    let entry_file = entry_path(script, mode.clone(), options);
+    let now = util::unix_timestamp(SystemTime::now());
+    // Rebuilds of the script retain the time it's been first ran.
+    let first_used = Entry::read(&entry_file).map(|e| e.first_used).unwrap_or(now);
+    let deps = match *backend {
+        Backend::Cargo{ref crate_dir, ..} => {
+            let manifest_path = crate_dir.join("Cargo.toml");
+            util::read_text_file(&manifest_path).ok()
+                .and_then(|content| toml::Parser::new(&content).parse())
+                .map(|manifest| manifest::dependency_names(&manifest))
+                .unwrap_or_default()
+        },
+        Backend::Rustc{..} => vec![],
+    };
+
+    let entry = Entry{
+        path: entry_file,
+        sha: script.sha1().result_str(),
+        options: Some(BuildOptions::from(options)),
+        binary: binary,
+        mtime: mtime,
+        script: script.source.to_string(),
+        script_path: script.path.clone(),
+        crate_dir: backend.crate_dir().to_owned(),
+        package: backend.bin_name().to_owned(),
+        deps: deps,
+        toolchain: options.toolchain.clone(),
+        build_mode: mode.profile().to_owned(),
+        first_used: first_used,
+        last_used: now,
+        build_time: build_time,
+        binary_size: binary_size,
+    };
+    let result = fs::create_dir_all(&*BINARY_INDEX_DIR).and_then(|_| entry.write());
+    match result {
+        Ok(_) => trace!("Script binary recorded in the index";
+            "script" => entry.script, "binary" => entry.binary.display().to_string()),
+        Err(err) => {
+            warn!("Failed to record the script binary in the index";
+                "path" => entry.path.display().to_string(), "error" => format!("{}", err));
+        },
+    }
+}
+
+
+/// Entry of the index of script binaries.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Path to the entry's file within the index.
+    pub path: PathBuf,
+    /// SHA of the script code (and its modules) that the binary has been built from.
+    sha: String,
+    /// Cargo options that the binary has been built with
+    /// (missing from entries recorded by older versions of runrs).
+    options: Option<BuildOptions>,
+    /// Path to the script's binary.
+    pub binary: PathBuf,
+    /// Modification time of the binary right after it's been built.
+    mtime: String,
+    /// Script that the binary has been built from (as given when it was ran).
+    pub script: String,
+    /// Absolute path to the script file, unless it came from stdin or the command line.
+    pub script_path: Option<PathBuf>,
+    /// Directory of the script crate that the binary has been built in.
+    pub crate_dir: PathBuf,
+    /// Name of the script's package (and its binary).
+    pub package: String,
+    /// Names of the script's dependencies.
+    pub deps: Vec<String>,
+    /// Rust toolchain that the binary has been built with, if not the default one.
+    pub toolchain: Option<String>,
+    /// Build mode (i.e. profile) of the binary.
+    pub build_mode: String,
+    /// Time the script has been first ran, in seconds since Unix epoch.
+    pub first_used: u64,
+    /// Time the binary was last used, in seconds since Unix epoch.
+    pub last_used: u64,
+    /// How long it took to build the binary.
+    pub build_time: Duration,
+    /// Size of the binary, in bytes.
+    pub binary_size: u64,
+}
+
+impl Entry {
+    /// Read the index entry from given file.
+    /// Metadata missing from entries recorded by older versions of runrs is left empty.
+    fn read(path: &Path) -> io::Result<Entry> {
+        let content = try!(util::read_text_file(path));
+        let table = try!(toml::Parser::new(&content).parse().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "malformed TOML")
+        }));
+
+        let string = |key: &str| table.get(key).and_then(|v| v.as_str()).map(str::to_owned);
+        let integer = |key: &str| table.get(key).and_then(|v| v.as_integer()).unwrap_or(0) as u64;
+        let binary = try!(string("binary").ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "no path to the binary")
+        }));
+        let strings = |key: &str| table.get(key).and_then(|v| v.as_slice())
+            .map(|vs| vs.iter().filter_map(|v| v.as_str()).map(str::to_owned).collect())
+            .unwrap_or_default();
+        let options = table.get("features").map(|_| BuildOptions{
+            features: strings("features"),
+            target: string("target"),
+            args: strings("cargo-args"),
+        });
+        Ok(Entry{
+            path: path.to_owned(),
+            sha: string("sha").unwrap_or_default(),
+            options: options,
+            binary: PathBuf::from(binary),
+            mtime: string("mtime").unwrap_or_default(),
+            script: string("script").unwrap_or_default(),
+            script_path: string("path").map(PathBuf::from),
+            crate_dir: string("crate").map(PathBuf::from).unwrap_or_default(),
+            package: string("package").unwrap_or_default(),
+            deps: strings("deps"),
+            toolchain: string("toolchain"),
+            build_mode: string("build-mode").unwrap_or_default(),
+            first_used: integer("first-used"),
+            last_used: integer("last-used"),
+            build_time: Duration::from_millis(integer("build-time-ms")),
+            binary_size: integer("binary-size"),
+        })
+    }
+
+    /// Write the entry into its file.
+    /// This is done atomically, so that concurrent runs of the same script never see it incomplete.
+    fn write(&self) -> io::Result<()> {
+        let mut table = toml::Table::new();
+        {
+            let mut set_str = |key: &str, value: &str| {
+                table.insert(key.into(), toml::Value::String(value.into()));
+            };
+            set_str("sha", &self.sha);
+            set_str("binary", &self.binary.to_string_lossy());
+            set_str("mtime", &self.mtime);
+            set_str("script", &self.script);
+            if let Some(ref path) = self.script_path {
+                set_str("path", &path.to_string_lossy());
+            }
+            set_str("crate", &self.crate_dir.to_string_lossy());
+            set_str("package", &self.package);
+            if let Some(ref toolchain) = self.toolchain {
+                set_str("toolchain", toolchain);
+            }
+            set_str("build-mode", &self.build_mode);
+            if let Some(BuildOptions{target: Some(ref target), ..}) = self.options {
+                set_str("target", target);
+            }
+        }
+        {
+            let mut set_strings = |key: &str, values: &[String]| {
+                let values = values.iter().cloned().map(toml::Value::String).collect();
+                table.insert(key.into(), toml::Value::Array(values));
+            };
+            set_strings("deps", &self.deps);
+            if let Some(ref options) = self.options {
+                set_strings("features", &options.features);
+                set_strings("cargo-args", &options.args);
+            }
+        }
+        let build_time_ms = self.build_time.as_secs() * 1000
+            + self.build_time.subsec_nanos() as u64 / 1_000_000;
+        for &(key, value) in &[("first-used", self.first_used), ("last-used", self.last_used),
+                               ("build-time-ms", build_time_ms),
+                               ("binary-size", self.binary_size)] {
+            table.insert(key.into(), toml::Value::Integer(value as i64));
+        }
+        util::write_file_atomic(&self.path, toml::encode_str(&table).as_bytes())
+    }
+
+    /// ID of the script that the entry is for.
+    pub fn script_id(&self) -> String {
+        self.crate_dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+
+    /// Check whether the binary can be used to run the script in its current form
+    /// (with given options), or why the script needs to be rebuilt.
+    pub fn check(&self, script: &Script, options: &CargoOptions) -> Result<(), Stale> {
+        // The binary must've been built from the same code and not touched since then
+        // (e.g. rebuilt by Cargo for another version of the script).
+        if self.sha != script.sha1().result_str() {
+            return Err(Stale::ScriptChanged);
+        }
+        if self.options.as_ref() != Some(&BuildOptions::from(options))
+                || self.toolchain != options.toolchain {
+            return Err(Stale::OptionsChanged);
+        }
+        let mtime = fs::metadata(&self.binary).and_then(|m| modification_time(&m)).ok();
+        if self.mtime.is_empty() || mtime.as_ref() != Some(&self.mtime) {
+            return Err(Stale::BinaryChanged);
+        }
+        Ok(())
+    }
+}
+
+/// Cargo options which affect the binary built for a script,
+/// as opposed to those that only affect how it's built (like --jobs or --offline).
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct BuildOptions {
+    /// Features to activate, in a canonical order.
+    features: Vec<String>,
+    target: Option<String>,
+    args: Vec<String>,
+}
+
+impl<'a> From<&'a CargoOptions> for BuildOptions {
+    fn from(options: &'a CargoOptions) -> Self {
+        // Features may be given as comma or space separated lists (like `--features "a b"`).
+        let mut features: Vec<String> = options.features.iter()
+            .flat_map(|f| f.split(|c: char| c == ',' || c.is_whitespace()))
+            .filter(|f| !f.is_empty())
+            .map(str::to_owned)
+            .collect();
+        features.sort();
+        features.dedup();
+        BuildOptions{
+            features: features,
+            target: options.target.clone(),
+            args: options.args.clone(),
+        }
+    }
+}
+
+/// Reason why the binary recorded for a script can't be used (so the script will be rebuilt).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stale {
+    /// The script (or any of its modules) has changed since its binary was built.
+    ScriptChanged,
+    /// The binary has been built with different Cargo options.
+    OptionsChanged,
+    /// The binary is missing or has been modified since it was built.
+    BinaryChanged,
+}
+
+impl fmt::Display for Stale {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stale::ScriptChanged => write!(fmt, "script has changed since it was built"),
+            Stale::OptionsChanged => write!(fmt, "it was built with different Cargo options"),
+            Stale::BinaryChanged => write!(fmt, "binary is missing or has been modified"),
+        }
+    }
+}
+
+
+/// Read all the entries of the index of script binaries.
+/// Entries recorded by older versions of runrs (without the crate directory) are skipped.
+pub fn entries() -> Vec<Entry> {
+    let dir_entries = match fs::read_dir(&*BINARY_INDEX_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    dir_entries.filter_map(|e| e.ok()).map(|e| e.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .filter_map(|path| Entry::read(&path).ok())
+        .filter(|entry| entry.crate_dir != Path::new(""))
+        .collect()
+}
+
+
+/// Path to the file with the index entry for given script, build mode and options.
+/// Binaries built with different toolchains get separate entries.
+fn entry_path(script: &Script, mode: BuildMode, options: &CargoOptions) -> PathBuf {
+    let name = match options.toolchain {
+        // Like for the workspace directories, path separators in (custom) toolchain names
+        // mustn't make the entry end up outside the index.
+        Some(ref toolchain) => format!("{}-{}-{}.toml",
+            script.id(), toolchain.replace(['/', '\\'], "_"), mode.profile()),
+        None => format!("{}-{}.toml", script.id(), mode.profile()),
+    };
+    BINARY_INDEX_DIR.join(name)
+}
+
+/// Modification time from given file metadata,
+/// as a string of seconds and nanoseconds since Unix epoch.
+fn modification_time(metadata: &fs::Metadata) -> io::Result<String> {
+    let mtime = try!(metadata.modified());
+    let since_epoch = try!(mtime.duration_since(UNIX_EPOCH).map_err(io::Error::other));
+    Ok(format!("{}.{:09}", since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cargo::{BuildMode, CargoOptions};
+    use script::{Script, Source};
+    use super::{entry_path, BINARY_INDEX_DIR};
+
+    #[test]
+    fn toolchain_in_entry_path_is_sanitized() {
+        let script = Script::load(Source::Eval("fn main() {}".into())).unwrap();
+        let options = CargoOptions{
+            toolchain: Some("../custom/tool\\chain".into()), ..CargoOptions::default()
+        };
+        let path = entry_path(&script, BuildMode::Release, &options);
+        assert_eq!(path.parent(), Some(BINARY_INDEX_DIR.as_path()));
+        assert_eq!(path.file_name().unwrap().to_string_lossy(),
+                   format!("{}-.._custom_tool_chain-release.toml", script.id()));
+    }
+}
@@ -1,9 +1,16 @@
 //! Module wrapping the interactions with Cargo.
 
-use std::path::Path;
-use std::process::{Command, exit};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
 
-use util::exitcode;
+use isatty;
+
+use error::Error;
+use util::{self, exitcode::{self, ExitCode}};
+use workspace;
 
 
 custom_derive! {
@@ -22,49 +29,343 @@ impl Default for BuildMode {
     fn default() -> Self { BuildMode::Debug }
 }
 
+impl BuildMode {
+    /// Name of the Cargo profile used in this mode
+    /// (which is also the name of its subdirectory within target/).
+    pub fn profile(&self) -> &'static str {
+        match *self {
+            BuildMode::Debug => "debug",
+            BuildMode::Release => "release",
+        }
+    }
+}
+
+
+/// Options that are passed through to Cargo commands.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CargoOptions {
+    /// Rust toolchain to run Cargo (or rustc) from, through rustup (like `cargo +nightly`).
+    pub toolchain: Option<String>,
+    /// Features of the script crate to enable (--features).
+    pub features: Vec<String>,
+    /// Target triple to build the script for (--target).
+    pub target: Option<String>,
+    /// Number of parallel build jobs (--jobs).
+    pub jobs: Option<u32>,
+    /// Whether Cargo should run without accessing the network (--offline).
+    pub offline: Option<bool>,
+    /// Whether Cargo.lock must be used as it is (--locked).
+    pub locked: Option<bool>,
+    /// Whether Cargo.lock must be used as it is and without accessing the network (--frozen).
+    pub frozen: Option<bool>,
+    /// Any other arguments to pass to Cargo (given with --cargo-arg).
+    pub args: Vec<String>,
+}
+
+impl CargoOptions {
+    /// Fill in the options that haven't been specified here with those from `other`,
+    /// which has lower precedence (e.g. it comes from the config file).
+    /// Lists of features and arguments are combined.
+    pub fn merge(&mut self, other: &CargoOptions) {
+        self.toolchain = self.toolchain.take().or_else(|| other.toolchain.clone());
+        for feature in &other.features {
+            if !self.features.contains(feature) {
+                self.features.push(feature.clone());
+            }
+        }
+        self.target = self.target.take().or_else(|| other.target.clone());
+        self.jobs = self.jobs.or(other.jobs);
+        self.offline = self.offline.or(other.offline);
+        self.locked = self.locked.or(other.locked);
+        self.frozen = self.frozen.or(other.frozen);
+        self.args.extend(other.args.iter().cloned());
+    }
+}
+
+
+/// Builder of Cargo commands, which passes the user's options to all of them.
+#[derive(Clone, Debug)]
+pub struct Cargo {
+    /// Cargo subcommand to run, like "build".
+    subcommand: &'static str,
+    /// Options given by the user.
+    options: CargoOptions,
+    /// Arguments specific to this command.
+    args: Vec<OsString>,
+}
+
+impl Cargo {
+    /// Start building a command that runs given Cargo subcommand.
+    pub fn new(subcommand: &'static str) -> Self {
+        Cargo{subcommand: subcommand, options: CargoOptions::default(), args: vec![]}
+    }
+
+    /// Pass given user's options to the command.
+    pub fn options(mut self, options: &CargoOptions) -> Self {
+        self.options = options.clone();
+        self
+    }
+
+    /// Add an argument that's specific to this command.
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Create the actual command to run.
+    pub fn command(&self) -> Command {
+        /// Subcommands which build the crate and thus accept options like --features.
+        const BUILD_SUBCOMMANDS: &'static [&'static str] = &["build", "check", "run"];
+
+        let mut cmd = tool_cmd("cargo", self.options.toolchain.as_deref());
+        cmd.arg(self.subcommand);
+        if self.options.offline == Some(true) { cmd.arg("--offline"); }
+        if self.options.locked == Some(true) { cmd.arg("--locked"); }
+        if self.options.frozen == Some(true) { cmd.arg("--frozen"); }
+        if BUILD_SUBCOMMANDS.contains(&self.subcommand) {
+            if !self.options.features.is_empty() {
+                cmd.arg("--features").arg(self.options.features.join(","));
+            }
+            if let Some(ref target) = self.options.target {
+                cmd.arg("--target").arg(target);
+            }
+            if let Some(jobs) = self.options.jobs {
+                cmd.arg("--jobs").arg(jobs.to_string());
+            }
+        }
+        cmd.args(&self.options.args).args(&self.args);
+        cmd
+    }
+}
+
 
-// TODO: make a Cargo wrapper struct where we can pass common options (in a Builder fashion)
-// before invoking a specific Cargo command
+/// Tool that builds the binary of a script.
+#[derive(Clone, Debug)]
+pub enum Backend {
+    /// Cargo, building a script crate within the workspace.
+    Cargo {
+        /// Directory of the script crate.
+        crate_dir: PathBuf,
+        /// Directory where Cargo puts the build artifacts.
+        target_dir: PathBuf,
+        /// Name of the crate's binary.
+        bin_name: String,
+        /// Options to pass to Cargo.
+        options: CargoOptions,
+    },
+    /// Plain rustc, compiling the script directly.
+    /// This is only possible for scripts that don't have any dependencies.
+    Rustc {
+        /// Source file of the script.
+        source: PathBuf,
+        /// Files to write before compiling the script (i.e. the source file,
+        /// and copies of some of the script's modules), with their content.
+        /// These are only there if the script can't be compiled from where it is
+        /// (like when it's read from stdin).
+        files: Vec<(PathBuf, String)>,
+        /// Rust edition to compile the script with.
+        edition: &'static str,
+        /// Directory where the build artifacts are put (in the same layout as Cargo uses).
+        out_dir: PathBuf,
+        /// Name of the binary.
+        bin_name: String,
+        /// Target triple to build the script for, if not the host.
+        target: Option<String>,
+        /// Rust toolchain to use, if not the default one.
+        toolchain: Option<String>,
+    },
+}
 
+impl Backend {
+    /// Build the script's binary in given mode.
+    pub fn build(&self, mode: BuildMode) -> Result<(), Error> {
+        let mut cmd = match *self {
+            Backend::Cargo{ref crate_dir, ref target_dir, ref options, ..} =>
+                cargo_build_cmd(crate_dir, target_dir, options, mode.clone()),
+            Backend::Rustc{ref source, ref files, edition, ref out_dir, ref bin_name,
+                           ref target, ref toolchain} => {
+                // Concurrent runs of the same script would otherwise overwrite each other's
+                // source file and binary, so the output directory stays locked during the build.
+                let _lock = try!(workspace::lock_workspace(out_dir));
+                for (path, content) in files {
+                    let dir = path.parent().unwrap_or(Path::new(""));
+                    try!(fs::create_dir_all(dir)
+                        .and_then(|_| util::write_file_atomic(path, content.as_bytes()))
+                        .map_err(|e| Error::Io(path.clone(), e)));
+                }
+                // Unlike Cargo, rustc won't create the output directory itself.
+                let profile_dir = self.profile_dir(mode.clone());
+                try!(fs::create_dir_all(&profile_dir)
+                    .map_err(|e| Error::Io(profile_dir.clone(), e)));
+                let mut cmd = tool_cmd("rustc", toolchain.as_ref().map(|t| t.as_str()));
+                add_rustc_args(&mut cmd, source, edition, target.as_ref().map(|t| t.as_str()),
+                               &profile_dir, bin_name, mode.clone());
+                cmd
+            },
+        };
 
-/// Execute `cargo run` within given directory.
-/// Regardless whether or not it succceeds, this function does not return.
-pub fn run<P: AsRef<Path>>(path: P, args: &[String], mode: BuildMode) -> ! {
-    let path = path.as_ref();
+        trace!("About to build the script";
+            "mode" => mode.profile(), "cmd" => format!("{:?}", cmd));
+        let exit_status = try!(cmd.status().map_err(|e| Error::Spawn(self.tool().into(), e)));
+        if !exit_status.success() {
+            return Err(Error::Build(exit_status));
+        }
+        Ok(())
+    }
 
-    let mut cmd = Command::new("cargo");
-    cmd.current_dir(path.clone())
-        .arg("run").arg("--quiet");  // TODO: don't make it --quiet if -v was passed
+    /// Name of the program that this backend runs to build the script.
+    fn tool(&self) -> &'static str {
+        match *self {
+            Backend::Cargo{ref options, ..} if options.toolchain.is_some() => "rustup",
+            Backend::Rustc{ref toolchain, ..} if toolchain.is_some() => "rustup",
+            Backend::Cargo{..} => "cargo",
+            Backend::Rustc{..} => "rustc",
+        }
+    }
+
+    /// Directory of the script crate (or the one with build artifacts of a standalone script).
+    pub fn crate_dir(&self) -> &Path {
+        match *self {
+            Backend::Cargo{ref crate_dir, ..} => crate_dir,
+            Backend::Rustc{ref out_dir, ..} => out_dir,
+        }
+    }
+
+    /// Name of the script's binary (which is also the script crate's package name).
+    pub fn bin_name(&self) -> &str {
+        match *self {
+            Backend::Cargo{ref bin_name, ..} | Backend::Rustc{ref bin_name, ..} => bin_name,
+        }
+    }
+
+    /// Path to the script's binary that's built in given mode.
+    pub fn binary_path(&self, mode: BuildMode) -> PathBuf {
+        let file_name = format!("{}{}", self.bin_name(), env::consts::EXE_SUFFIX);
+        self.profile_dir(mode).join(file_name)
+    }
+
+    /// Directory where the build artifacts of given mode are put.
+    /// Like with Cargo, builds for an explicit target get a separate subdirectory.
+    fn profile_dir(&self, mode: BuildMode) -> PathBuf {
+        let (dir, target) = match *self {
+            Backend::Cargo{ref target_dir, ref options, ..} => (target_dir, &options.target),
+            Backend::Rustc{ref out_dir, ref target, ..} => (out_dir, target),
+        };
+        match *target {
+            Some(ref target) => dir.join(target).join(mode.profile()),
+            None => dir.join(mode.profile()),
+        }
+    }
+}
+
+
+/// Prepare the `cargo build` command for the crate within given directory.
+fn cargo_build_cmd(crate_dir: &Path, target_dir: &Path,
+                   options: &CargoOptions, mode: BuildMode) -> Command {
+    // The target directory is given explicitly (and Cargo is ran in the crate's directory),
+    // so that Cargo config of whatever project the user is in can't put the binary elsewhere
+    // than where we'll look for it.
+    let mut cargo = Cargo::new("build").options(options)
+        .arg("--quiet")  // TODO: don't make it --quiet if -v was passed
+        .arg("--manifest-path").arg(crate_dir.join("Cargo.toml"))
+        .arg("--target-dir").arg(target_dir);
     if mode == BuildMode::Release {
-        cmd.arg("--release");
+        cargo = cargo.arg("--release");
     }
-    if !args.is_empty() {
-        cmd.arg("--").args(args);
+    let mut cmd = cargo.command();
+    cmd.current_dir(crate_dir);
+    cmd
+}
+
+/// Add the arguments to rustc command for compiling given source file into a binary
+/// within given directory, with the same settings Cargo would use for its default profiles.
+fn add_rustc_args(cmd: &mut Command, source: &Path, edition: &str, target: Option<&str>,
+                  profile_dir: &Path, bin_name: &str, mode: BuildMode) {
+    // Crate name must be a valid (ASCII) identifier, unlike the binary's name.
+    let crate_name: String = bin_name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+
+    cmd.arg("--edition").arg(edition)
+        .arg("--crate-type").arg("bin")
+        .arg("--crate-name").arg(crate_name)
+        .args(["--color", if isatty::stderr_isatty() { "always" } else { "never" }])
+        .arg("-o").arg(profile_dir.join(format!("{}{}", bin_name, env::consts::EXE_SUFFIX)));
+    match mode {
+        BuildMode::Debug => {
+            let mut incremental = OsString::from("incremental=");
+            incremental.push(profile_dir.join("incremental"));
+            cmd.args(["-C", "debuginfo=2"]).arg("-C").arg(incremental);
+        },
+        BuildMode::Release => { cmd.args(["-C", "opt-level=3"]); },
+    }
+    if let Some(target) = target {
+        cmd.arg("--target").arg(target);
     }
+    cmd.arg(source);
+}
 
-    trace!("About to `cargo run`";
-        "dir" => path.display().to_string(), "cmd" => format!("{:?}", cmd));
+/// Prepare the command for running given Rust tool (like cargo or rustc).
+/// If a specific toolchain is requested, the tool is ran from it through rustup.
+fn tool_cmd(tool: &str, toolchain: Option<&str>) -> Command {
+    match toolchain {
+        Some(toolchain) => {
+            let mut cmd = Command::new("rustup");
+            cmd.arg("run").arg(toolchain).arg(tool);
+            cmd
+        },
+        None => Command::new(tool),
+    }
+}
+
+
+/// Execute the binary built for the script, passing it given arguments.
+/// The binary is ran in the current directory, with `argv0` as its program name.
+///
+/// On Unix, this function only returns if the binary couldn't be executed.
+/// Elsewhere, it returns the script's exit code that the application should exit with.
+pub fn run<P: AsRef<Path>>(binary: P, argv0: &str, args: &[String]) -> Result<ExitCode, Error> {
+    let binary = binary.as_ref();
+    let mut cmd = script_cmd(binary, argv0, args);
 
-    // On Unix, we can replace the app's process completely with Cargo
+    // On Unix, we can replace the app's process completely with the script
     // but on Windows, we have to run its as a child process and wait for it.
     if cfg!(unix) {
         use std::os::unix::process::CommandExt;
 
         // This calls execvp() and doesn't return unless an error occurred.
         let error = cmd.exec();
-        debug!("`cargo run` failed";
-            "dir" => path.display().to_string(), "error" => format!("{}", error));
-
-        panic!("Failed to execute the script: {}", error);
+        Err(Error::Run(binary.to_owned(), error))
     } else {
-        let mut run = cmd.spawn()
-            .unwrap_or_else(|e| panic!("Failed to execute the script: {}", e));
+        let status = try!(cmd.status().map_err(|e| Error::Run(binary.to_owned(), e)));
+        if !status.success() {
+            return Err(Error::Failed(Output{status: status, stdout: vec![], stderr: vec![]}));
+        }
+        Ok(exitcode::EX_OK)
+    }
+}
 
-        // Propagate the same exit code that Cargo -- and conversely, the script -- returned.
-        let exit_status = run.wait().unwrap_or_else(|e| {
-            panic!("Failed to obtain status code for the script: {}", e)
-        });
-        let exit_code = exit_status.code().unwrap_or(exitcode::EX_TEMPFAIL);
-        exit(exit_code);
+/// Execute the binary built for the script like `run` does,
+/// but wait for it to finish and capture its output.
+pub fn output<P: AsRef<Path>>(binary: P, argv0: &str, args: &[String]) -> Result<Output, Error> {
+    let binary = binary.as_ref();
+    let output = try!(script_cmd(binary, argv0, args).output()
+        .map_err(|e| Error::Run(binary.to_owned(), e)));
+    if !output.status.success() {
+        return Err(Error::Failed(output));
     }
+    Ok(output)
+}
+
+/// Prepare the command for executing the binary built for the script.
+fn script_cmd(binary: &Path, argv0: &str, args: &[String]) -> Command {
+    let mut cmd = Command::new(binary);
+    cmd.args(args);
+    if cfg!(unix) {
+        use std::os::unix::process::CommandExt;
+        cmd.arg0(argv0);
+    }
+
+    trace!("About to run the script binary";
+        "binary" => binary.display().to_string(), "argv0" => argv0, "cmd" => format!("{:?}", cmd));
+    cmd
 }
@@ -0,0 +1,195 @@
+//! Module implementing the subcommands of runrs, other than running a script.
+
+use std::cmp::Reverse;
+use std::time::{Duration, SystemTime};
+
+use runrs::{Config, Error, Script, ScriptOptions, Source};
+use runrs::cache::{self, Entry};
+use runrs::gc::{self, Policy};
+use runrs::exitcode::{self, ExitCode};
+
+use args::Subcommand;
+
+
+/// Execute given subcommand.
+/// Returns the exit code that the application should exit with.
+pub fn run(subcommand: Subcommand, config: &Config) -> Result<ExitCode, Error> {
+    match subcommand {
+        Subcommand::Gc{policy, dry_run} => collect_garbage(policy, dry_run, config),
+        Subcommand::List{json} => list_scripts(json),
+        Subcommand::Info{script} => show_script_info(script, config),
+    }
+}
+
+
+/// Evict the script crates that exceed given limits, or those from the configuration.
+fn collect_garbage(mut policy: Policy, dry_run: bool, config: &Config) -> Result<ExitCode, Error> {
+    policy.merge(&config.gc);
+    if policy.is_empty() {
+        error!("No limits given for evicting script crates (see `runrs gc --help`)");
+        return Ok(exitcode::EX_USAGE);
+    }
+
+    let report = try!(gc::collect(&policy, dry_run, None));
+    let now = runrs::unix_timestamp(SystemTime::now());
+    for krate in &report.evicted {
+        println!("{} {} ({}, last used {}, {})",
+            if dry_run { "Would evict" } else { "Evicted" }, krate.id(),
+            krate.script.as_deref().unwrap_or("unknown script"),
+            format_age(now.saturating_sub(krate.last_used)), runrs::format_size(krate.size));
+    }
+    println!("{} {} script crate(s), freeing {}; {} kept",
+        if dry_run { "Would evict" } else { "Evicted" }, report.evicted.len(),
+        runrs::format_size(report.freed()), report.kept);
+    Ok(exitcode::EX_OK)
+}
+
+
+/// List the scripts that have been built, most recently used first.
+fn list_scripts(json: bool) -> Result<ExitCode, Error> {
+    let mut entries = cache::entries();
+    entries.sort_by_key(|entry| Reverse(entry.last_used));
+    if json {
+        let items: Vec<_> = entries.iter().map(entry_json).collect();
+        println!("[{}]", items.join(","));
+        return Ok(exitcode::EX_OK);
+    }
+    if entries.is_empty() {
+        println!("No scripts have been built yet");
+        return Ok(exitcode::EX_OK);
+    }
+
+    let now = runrs::unix_timestamp(SystemTime::now());
+    let mut rows = vec![vec!["SCRIPT".to_owned(), "MODE".into(), "TOOLCHAIN".into(),
+                             "DEPS".into(), "LAST USED".into(), "BUILD TIME".into(),
+                             "SIZE".into()]];
+    rows.extend(entries.iter().map(|entry| vec![
+        entry.script.clone(),
+        entry.build_mode.clone(),
+        entry.toolchain.clone().unwrap_or_else(|| "default".into()),
+        entry.deps.len().to_string(),
+        format_age(now.saturating_sub(entry.last_used)),
+        format_duration(entry.build_time),
+        runrs::format_size(entry.binary_size),
+    ]));
+    let mut widths = vec![0; rows[0].len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = cell.chars().count().max(*width);
+        }
+    }
+    for row in &rows {
+        // The last column is left unpadded, so there's no trailing whitespace.
+        let (last, cells) = row.split_last().unwrap();
+        let cells: Vec<_> = cells.iter().zip(&widths)
+            .map(|(cell, &width)| format!("{:1$}", cell, width)).collect();
+        println!("{}  {}", cells.join("  "), last);
+    }
+    Ok(exitcode::EX_OK)
+}
+
+/// Show the builds of given script, and whether it would be rebuilt if it was ran now.
+fn show_script_info(source: Source, config: &Config) -> Result<ExitCode, Error> {
+    let script = try!(Script::load(source.clone()).map_err(|e| Error::Script(source.clone(), e)));
+    let id = script.id();
+    let mut entries: Vec<_> = cache::entries().into_iter()
+        .filter(|entry| entry.script_id() == id).collect();
+    entries.sort_by_key(|entry| Reverse(entry.last_used));
+
+    println!("Script:       {}", script.source);
+    println!("ID:           {}", id);
+    if entries.is_empty() {
+        println!("Status:       not built yet");
+        return Ok(exitcode::EX_OK);
+    }
+    let now = runrs::unix_timestamp(SystemTime::now());
+    // Builds are checked against the options that running the script would use,
+    // i.e. those from its shebang and header, and then the config.
+    let mut script_options = try!(ScriptOptions::from_script(&script)).cargo;
+    script_options.merge(&config.cargo);
+    for entry in &entries {
+        // Each build also has the toolchain it's been built with,
+        // since that's what tells the builds apart.
+        let mut options = script_options.clone();
+        options.toolchain = entry.toolchain.clone();
+        let status = match entry.check(&script, &options) {
+            Ok(()) => "up to date".to_owned(),
+            Err(reason) => format!("would be rebuilt ({})", reason),
+        };
+
+        println!();
+        println!("Build mode:   {}", entry.build_mode);
+        println!("Toolchain:    {}", entry.toolchain.as_ref().map_or("default", |t| t.as_str()));
+        println!("Status:       {}", status);
+        println!("Package:      {}", entry.package);
+        println!("Crate:        {}", entry.crate_dir.display());
+        println!("Dependencies: {}",
+            if entry.deps.is_empty() { "none".into() } else { entry.deps.join(", ") });
+        println!("Binary:       {} ({})",
+            entry.binary.display(), runrs::format_size(entry.binary_size));
+        println!("Build time:   {}", format_duration(entry.build_time));
+        println!("First run:    {}", format_age(now.saturating_sub(entry.first_used)));
+        println!("Last run:     {}", format_age(now.saturating_sub(entry.last_used)));
+    }
+    Ok(exitcode::EX_OK)
+}
+
+/// Format the entry of the index of script binaries as a JSON object.
+fn entry_json(entry: &Entry) -> String {
+    let opt_str = |s: Option<&str>| s.map_or("null".into(), json_str);
+    let deps: Vec<_> = entry.deps.iter().map(|d| json_str(d)).collect();
+    let script_path = entry.script_path.as_ref().map(|p| p.to_string_lossy().into_owned());
+    format!("{{\"id\":{},\"script\":{},\"path\":{},\"package\":{},\"deps\":[{}],\
+             \"toolchain\":{},\"build_mode\":{},\"crate_dir\":{},\"binary\":{},\
+             \"binary_size\":{},\"build_time\":{:.3},\"first_used\":{},\"last_used\":{}}}",
+        json_str(&entry.script_id()), json_str(&entry.script),
+        opt_str(script_path.as_deref()), json_str(&entry.package),
+        deps.join(","), opt_str(entry.toolchain.as_deref()),
+        json_str(&entry.build_mode), json_str(&entry.crate_dir.to_string_lossy()),
+        json_str(&entry.binary.to_string_lossy()), entry.binary_size,
+        duration_secs(entry.build_time), entry.first_used, entry.last_used)
+}
+
+/// Format given string as a JSON string literal.
+fn json_str(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+
+/// Format given duration in seconds, like "12.3s".
+fn format_duration(duration: Duration) -> String {
+    format!("{:.1}s", duration_secs(duration))
+}
+
+/// Given duration as (fractional) number of seconds.
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Format given number of seconds as a rough, human-readable time ago, like "3 days ago".
+fn format_age(secs: u64) -> String {
+    const UNITS: &'static [(u64, &'static str)] = &[
+        (24 * 60 * 60, "day"), (60 * 60, "hour"), (60, "minute"),
+    ];
+    for &(unit_secs, unit) in UNITS {
+        let count = secs / unit_secs;
+        if count > 0 {
+            return format!("{} {}{} ago", count, unit, if count == 1 { "" } else { "s" });
+        }
+    }
+    "just now".into()
+}
@@ -0,0 +1,231 @@
+//! Module handling the user's configuration file.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use toml;
+
+use super::APP_DIR;
+use cargo::CargoOptions;
+use gc;
+use util::{self, exitcode};
+
+
+lazy_static! {
+    /// Path to the configuration file.
+    pub static ref CONFIG_FILE: PathBuf = APP_DIR.join("config.toml");
+}
+
+
+/// User configuration of runrs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Mapping of crate names (as used in `extern crate`) to names of their packages.
+    ///
+    /// This augments (and takes precedence over) the built-in mapping.
+    /// It's specified in the [packages] section of the config file, e.g.:
+    ///
+    /// ```toml
+    /// [packages]
+    /// crypto = "rust-crypto"
+    /// ```
+    pub packages: HashMap<String, String>,
+    /// Exit code that runrs returns when the script fails to compile,
+    /// so that it can be told apart from the exit code of the script itself.
+    ///
+    /// It's specified as `build-failure-exit-code` in the config file,
+    /// and defaults to EX_SOFTWARE (70).
+    pub build_failure_exit_code: exitcode::ExitCode,
+    /// Options passed to every Cargo invocation that builds a script.
+    ///
+    /// Those given on the command line or in the script itself take precedence.
+    /// They're specified in the [cargo] section of the config file, e.g.:
+    ///
+    /// ```toml
+    /// [cargo]
+    /// toolchain = "stable"
+    /// offline = true
+    /// jobs = 4
+    /// args = ["--config", "net.git-fetch-with-cli=true"]
+    /// ```
+    pub cargo: CargoOptions,
+    /// Limits on the script crates that runrs keeps around, enforced by `runrs gc`.
+    ///
+    /// They're specified in the [gc] section of the config file, with the maximum age in days
+    /// and the maximum size either in bytes or with a unit, e.g.:
+    ///
+    /// ```toml
+    /// [gc]
+    /// max-age = 30
+    /// max-count = 100
+    /// max-size = "2G"
+    /// auto = true
+    /// ```
+    pub gc: gc::Policy,
+    /// Whether the limits in `gc` should be enforced automatically whenever a script is built.
+    pub auto_gc: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config{
+            packages: HashMap::new(),
+            build_failure_exit_code: exitcode::EX_SOFTWARE,
+            cargo: CargoOptions::default(),
+            gc: gc::Policy::default(),
+            auto_gc: false,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from the config file.
+    /// If the file doesn't exist, default configuration is returned.
+    pub fn load() -> Result<Config, ConfigError> {
+        if !CONFIG_FILE.exists() {
+            trace!("Config file not found, using default configuration";
+                "path" => CONFIG_FILE.display().to_string());
+            return Ok(Config::default());
+        }
+
+        let content = try!(util::read_text_file(&*CONFIG_FILE).map_err(ConfigError::Io));
+        let mut parser = toml::Parser::new(&content);
+        let root = match parser.parse() {
+            Some(table) => table,
+            None => return Err(ConfigError::Parse(parser.errors.remove(0))),
+        };
+
+        let mut config = Config::default();
+        if let Some(packages) = root.get("packages") {
+            let packages = try!(packages.as_table().ok_or_else(|| {
+                ConfigError::Invalid("[packages] must be a table".into())
+            }));
+            for (crate_name, package) in packages {
+                let package = try!(package.as_str().ok_or_else(|| ConfigError::Invalid(
+                    format!("package name for crate `{}` must be a string", crate_name))));
+                config.packages.insert(crate_name.clone(), package.to_owned());
+            }
+        }
+        if let Some(code) = root.get("build-failure-exit-code") {
+            // Exit codes are truncated to a byte, so anything else would end up as some other code
+            // (like 256 as 0, which would make build failures look like success).
+            let code = try!(code.as_integer().filter(|c| 0 <= *c && *c <= 255).ok_or_else(|| {
+                ConfigError::Invalid("build-failure-exit-code must be an integer from 0 to 255".into())
+            }));
+            config.build_failure_exit_code = code as exitcode::ExitCode;
+        }
+        if let Some(cargo) = root.get("cargo") {
+            let cargo = try!(cargo.as_table().ok_or_else(|| {
+                ConfigError::Invalid("[cargo] must be a table".into())
+            }));
+            config.cargo = try!(parse_cargo_options(cargo));
+        }
+        if let Some(gc) = root.get("gc") {
+            let gc = try!(gc.as_table().ok_or_else(|| {
+                ConfigError::Invalid("[gc] must be a table".into())
+            }));
+            config.auto_gc = try!(gc.get("auto").map_or(Ok(false), |auto| {
+                auto.as_bool()
+                    .ok_or_else(|| ConfigError::Invalid("gc.auto must be a boolean".into()))
+            }));
+            config.gc = try!(parse_gc_policy(gc));
+        }
+
+        debug!("Configuration loaded"; "path" => CONFIG_FILE.display().to_string());
+        Ok(config)
+    }
+}
+
+/// Parse the [cargo] section of the config file.
+fn parse_cargo_options(table: &toml::Table) -> Result<CargoOptions, ConfigError> {
+    let mut options = CargoOptions::default();
+    for (key, value) in table {
+        let invalid = |what: &str| ConfigError::Invalid(format!("cargo.{} must be {}", key, what));
+        match key.as_str() {
+            "toolchain" => options.toolchain = Some(try!(value.as_str().ok_or_else(|| {
+                invalid("a string")
+            })).to_owned()),
+            "features" => options.features = try!(string_array(value).ok_or_else(|| {
+                invalid("an array of strings")
+            })),
+            "target" => options.target = Some(try!(value.as_str().ok_or_else(|| {
+                invalid("a string")
+            })).to_owned()),
+            "jobs" => options.jobs = Some(try!(value.as_integer()
+                .and_then(|n| if n > 0 { Some(n as u32) } else { None })
+                .ok_or_else(|| invalid("a positive integer")))),
+            "offline" => options.offline = Some(try!(value.as_bool().ok_or_else(|| {
+                invalid("a boolean")
+            }))),
+            "locked" => options.locked = Some(try!(value.as_bool().ok_or_else(|| {
+                invalid("a boolean")
+            }))),
+            "frozen" => options.frozen = Some(try!(value.as_bool().ok_or_else(|| {
+                invalid("a boolean")
+            }))),
+            "args" => options.args = try!(string_array(value).ok_or_else(|| {
+                invalid("an array of strings")
+            })),
+            _ => return Err(ConfigError::Invalid(format!("unknown option cargo.{}", key))),
+        }
+    }
+    Ok(options)
+}
+
+/// Parse the [gc] section of the config file (save for the `auto` flag).
+fn parse_gc_policy(table: &toml::Table) -> Result<gc::Policy, ConfigError> {
+    let mut policy = gc::Policy::default();
+    for (key, value) in table {
+        let invalid = |what: &str| ConfigError::Invalid(format!("gc.{} must be {}", key, what));
+        match key.as_str() {
+            "auto" => {},
+            "max-age" => policy.max_age = Some(try!(value.as_integer()
+                .and_then(|n| if n >= 0 { gc::days(n as u64) } else { None })
+                .ok_or_else(|| invalid("a number of days")))),
+            "max-count" => policy.max_count = Some(try!(value.as_integer()
+                .and_then(|n| if n >= 0 { Some(n as usize) } else { None })
+                .ok_or_else(|| invalid("a non-negative integer")))),
+            "max-size" => policy.max_size = Some(try!(match *value {
+                toml::Value::Integer(n) if n >= 0 => Some(n as u64),
+                toml::Value::String(ref s) => util::parse_size(s),
+                _ => None,
+            }.ok_or_else(|| invalid("a size in bytes or with a unit (like \"500M\")")))),
+            _ => return Err(ConfigError::Invalid(format!("unknown option gc.{}", key))),
+        }
+    }
+    Ok(policy)
+}
+
+/// Convert a TOML value to a vector of strings, if it's an array of them.
+fn string_array(value: &toml::Value) -> Option<Vec<String>> {
+    value.as_slice().and_then(|values| {
+        values.iter().map(|v| v.as_str().map(str::to_owned)).collect()
+    })
+}
+
+
+/// Error that can occur while loading the configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Error while reading the config file.
+    Io(io::Error),
+    /// Config file is not a valid TOML.
+    Parse(toml::ParserError),
+    /// Config file has an invalid value.
+    Invalid(String),
+}
+
+impl Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(fmt, "can't read the config file: {}", e),
+            ConfigError::Parse(ref e) => write!(fmt, "malformed config file: {}", e),
+            ConfigError::Invalid(ref msg) => write!(fmt, "invalid configuration: {}", msg),
+        }
+    }
+}
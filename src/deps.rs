@@ -0,0 +1,581 @@
+//! Module for extracting dependencies of a script from its code.
+
+use std::collections::{BTreeMap, HashSet};
+
+use proc_macro2::{TokenStream, TokenTree};
+use syn::{self, Arm, Attribute, Expr, ExprAsync, ExprAwait, Field, FieldValue, ForeignItem,
+          ImplItem, Item, ItemExternCrate, ItemUse, Local, Macro, Path, Signature, StmtMacro,
+          TraitItem, UseTree, Variant};
+use syn::visit::{self, Visit};
+
+
+/// Crates that are shipped with Rust itself and thus aren't dependencies.
+const BUILTIN_CRATES: &'static [&'static str] = &["std", "core", "alloc", "proc_macro", "test"];
+
+/// Names which can begin a path but never refer to an external crate.
+/// This includes path keywords, primitive types, and tool attributes' namespaces.
+const NON_CRATE_NAMES: &'static [&'static str] = &[
+    "crate", "self", "super",
+    "bool", "char", "str",
+    "f32", "f64", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+    "clippy", "diagnostic", "rustdoc", "rustfmt",
+];
+
+/// Configuration options that can be used in `cfg(...)` conditions of target-specific
+/// Cargo dependencies (aside from `target_*` ones, and `all`/`any`/`not` combinators).
+const TARGET_CFGS: &'static [&'static str] = &["unix", "windows"];
+
+/// Edition for scripts that use the 2018+ style of referring to external crates.
+const MODERN_EDITION: &'static str = "2021";
+/// Edition for scripts that only use `extern crate` to refer to external crates.
+const LEGACY_EDITION: &'static str = "2015";
+
+
+/// Dependency of a script, as inferred from its code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dependency {
+    /// Name of the crate (as used in `extern crate` or in paths).
+    pub crate_name: String,
+    /// Target-specific `#[cfg]` condition the dependency is gated by, if any
+    /// (e.g. `target_os = "linux"`).
+    pub cfg: Option<String>,
+    /// Whether the crate is declared with `extern crate`,
+    /// as opposed to just being referred to by paths (which requires the 2018 edition).
+    pub extern_crate: bool,
+}
+
+
+/// Dependencies of a script, along with what its code tells about its edition.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptDeps {
+    /// Crates that the script depends on.
+    pub crates: Vec<Dependency>,
+    /// Whether the script uses syntax that's only available since the 2018 edition
+    /// (like `async` or `.await`).
+    modern_syntax: bool,
+}
+
+impl ScriptDeps {
+    /// Determine the Rust edition the script should be compiled with.
+    ///
+    /// Scripts that declare all their dependencies with `extern crate` may be from before
+    /// the 2018 edition (and e.g. use `try!`), unless their syntax tells otherwise.
+    /// Any other script is assumed to be written in modern Rust.
+    pub fn edition(&self) -> &'static str {
+        let legacy = !self.crates.is_empty() && self.crates.iter().all(|d| d.extern_crate);
+        if legacy && !self.modern_syntax { LEGACY_EDITION } else { MODERN_EDITION }
+    }
+}
+
+
+/// Extract dependencies from script code,
+/// which includes the script itself as well as the files of its modules.
+pub fn extract<'c, I>(sources: I) -> Result<ScriptDeps, syn::Error>
+    where I: IntoIterator<Item=&'c str>
+{
+    // Names defined in one file can be used in paths in another (e.g. `helpers::foo()`),
+    // so all of them are visited before deciding which path roots are crates.
+    let mut visitor = DepsVisitor::default();
+    for code in sources {
+        let file = try!(syn::parse_file(code));
+        visitor.visit_file(&file);
+    }
+
+    // Crates declared with `extern crate` are certainly dependencies.
+    let mut deps = visitor.extern_crates;
+    deps.sort_by(|a, b| (&a.crate_name, &a.cfg).cmp(&(&b.crate_name, &b.cfg)));
+    deps.dedup();
+
+    // Other path roots are dependencies only if they don't refer to anything defined locally.
+    for (crate_name, cfg) in visitor.path_roots {
+        if deps.iter().any(|d| d.crate_name == crate_name)
+                || visitor.local_names.contains(&crate_name) {
+            continue;
+        }
+        deps.push(Dependency{crate_name: crate_name, cfg: cfg, extern_crate: false});
+    }
+    Ok(ScriptDeps{crates: deps, modern_syntax: visitor.modern_syntax})
+}
+
+
+/// AST visitor that collects potential dependencies of a script,
+/// keeping track of the #[cfg] conditions they are nested in.
+#[derive(Default)]
+struct DepsVisitor {
+    /// Stack of the `#[cfg]` conditions of enclosing items.
+    cfgs: Vec<String>,
+    /// Crates declared with `extern crate`.
+    extern_crates: Vec<Dependency>,
+    /// First segments of `use` and absolute paths that may refer to external crates,
+    /// with their #[cfg] conditions.
+    path_roots: BTreeMap<String, Option<String>>,
+    /// Names of items defined or imported by the script,
+    /// which can begin a path without referring to a crate.
+    local_names: HashSet<String>,
+    /// Whether any syntax that requires the 2018 edition has been encountered.
+    modern_syntax: bool,
+}
+
+impl DepsVisitor {
+    /// Visit the inside of an item with given attributes,
+    /// taking into account any #[cfg] conditions it may have.
+    fn with_cfgs<F: FnOnce(&mut Self)>(&mut self, attrs: &[Attribute], f: F) {
+        let cfg_count = self.cfgs.len();
+        self.cfgs.extend(attrs.iter().filter_map(cfg_condition));
+        f(self);
+        self.cfgs.truncate(cfg_count);
+    }
+
+    /// Determine the #[cfg] condition of a dependency based on the conditions it's nested in.
+    /// Returns None if the dependency isn't needed at all.
+    fn dependency_cfg(&self, crate_name: &str) -> Option<Option<String>> {
+        // Test-only crates are never needed, as we don't build the scripts' tests.
+        if self.cfgs.iter().any(|cfg| cfg == "test") {
+            trace!("Skipping test-only dependency"; "crate" => crate_name);
+            return None;
+        }
+
+        let cfg = match self.cfgs.len() {
+            0 => return Some(None),
+            1 => self.cfgs[0].clone(),
+            _ => format!("all({})", self.cfgs.join(", ")),
+        };
+        // Cargo can only choose dependencies based on the target platform,
+        // so any other conditions are treated as always true.
+        if is_target_cfg(&cfg) {
+            Some(Some(cfg))
+        } else {
+            debug!("Dependency has a non-target #[cfg], treating it as unconditional";
+                "crate" => crate_name, "cfg" => cfg);
+            Some(None)
+        }
+    }
+
+    /// Record the root of a path that may refer to an external crate.
+    fn add_path_root(&mut self, name: String) {
+        if BUILTIN_CRATES.contains(&name.as_str()) || NON_CRATE_NAMES.contains(&name.as_str()) {
+            return;
+        }
+        if let Some(cfg) = self.dependency_cfg(&name) {
+            // If the crate is ever used unconditionally, it's an unconditional dependency.
+            let entry = self.path_roots.entry(name).or_insert_with(|| cfg.clone());
+            if cfg.is_none() {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Look for paths that may refer to external crates (and for 2018 edition keywords)
+    /// in the tokens of a macro invocation, which syn can't parse as any particular syntax.
+    fn visit_macro_tokens(&mut self, tokens: TokenStream) {
+        let tokens: Vec<_> = tokens.into_iter().collect();
+        for (i, tt) in tokens.iter().enumerate() {
+            let punct_at = |j: usize, ch: char| j < tokens.len() && match tokens[j] {
+                TokenTree::Punct(ref p) => p.as_char() == ch,
+                _ => false,
+            };
+            match *tt {
+                TokenTree::Group(ref group) => self.visit_macro_tokens(group.stream()),
+                TokenTree::Ident(ref ident) => {
+                    let name = ident.to_string();
+                    if name == "async" || (name == "await" && i > 0 && punct_at(i - 1, '.')) {
+                        self.modern_syntax = true;
+                    }
+                    // Path roots are the identifiers followed by `::` and another segment
+                    // (rather than generic arguments, like in `.collect::<Vec<_>>()`),
+                    // but not preceded by `::`, `.` or `$` (like `$crate` in macro definitions).
+                    let ident_at = |j: usize| matches!(tokens.get(j), Some(&TokenTree::Ident(_)));
+                    let is_root = punct_at(i + 1, ':') && punct_at(i + 2, ':') && ident_at(i + 3)
+                        && !(i > 0 && [':', '.', '$'].iter().any(|&ch| punct_at(i - 1, ch)));
+                    if is_root && name.starts_with(|c: char| c.is_lowercase()) {
+                        self.add_path_root(name);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+// Any piece of code can be gated by #[cfg], so the conditions are tracked on everything
+// that may have attributes (and contain paths), rather than only on items like modules.
+impl<'ast> Visit<'ast> for DepsVisitor {
+    fn visit_item(&mut self, item: &'ast Item) {
+        let name = match *item {
+            Item::Const(ref i) => Some(&i.ident),
+            Item::Enum(ref i) => Some(&i.ident),
+            Item::ExternCrate(ref i) => Some(i.rename.as_ref().map(|r| &r.1).unwrap_or(&i.ident)),
+            Item::Fn(ref i) => Some(&i.sig.ident),
+            Item::Mod(ref i) => Some(&i.ident),
+            Item::Static(ref i) => Some(&i.ident),
+            Item::Struct(ref i) => Some(&i.ident),
+            Item::Trait(ref i) => Some(&i.ident),
+            Item::TraitAlias(ref i) => Some(&i.ident),
+            Item::Type(ref i) => Some(&i.ident),
+            Item::Union(ref i) => Some(&i.ident),
+            _ => None,
+        };
+        if let Some(name) = name {
+            self.local_names.insert(name.to_string());
+        }
+        self.with_cfgs(item_attrs(item), |this| visit::visit_item(this, item));
+    }
+
+    fn visit_item_extern_crate(&mut self, item: &'ast ItemExternCrate) {
+        let crate_name = item.ident.to_string();
+        if crate_name == "self" || BUILTIN_CRATES.contains(&crate_name.as_str()) {
+            return;
+        }
+        if let Some(cfg) = self.dependency_cfg(&crate_name) {
+            self.extern_crates.push(Dependency{
+                crate_name: crate_name, cfg: cfg, extern_crate: true,
+            });
+        }
+    }
+
+    fn visit_item_use(&mut self, item: &'ast ItemUse) {
+        /// Collect the names that a `use` tree imports into the scope.
+        fn imported_names(tree: &UseTree, names: &mut Vec<String>) {
+            match *tree {
+                UseTree::Path(ref p) => imported_names(&p.tree, names),
+                UseTree::Name(ref n) => names.push(n.ident.to_string()),
+                UseTree::Rename(ref r) => names.push(r.rename.to_string()),
+                UseTree::Glob(_) => {},
+                UseTree::Group(ref g) => for tree in &g.items { imported_names(tree, names) },
+            }
+        }
+        /// Collect the first segments of paths in a `use` tree.
+        fn path_roots(tree: &UseTree, roots: &mut Vec<String>) {
+            match *tree {
+                UseTree::Path(ref p) => roots.push(p.ident.to_string()),
+                UseTree::Name(ref n) => roots.push(n.ident.to_string()),
+                UseTree::Rename(ref r) => roots.push(r.ident.to_string()),
+                UseTree::Glob(_) => {},
+                UseTree::Group(ref g) => for tree in &g.items { path_roots(tree, roots) },
+            }
+        }
+
+        let mut names = vec![];
+        imported_names(&item.tree, &mut names);
+        let mut use_roots = vec![];
+        path_roots(&item.tree, &mut use_roots);
+
+        // A crate imported with a plain `use foo;` is both a root and an imported name,
+        // but it obviously doesn't make it local.
+        for name in names {
+            if !use_roots.contains(&name) {
+                self.local_names.insert(name);
+            }
+        }
+        for root in use_roots {
+            self.add_path_root(root);
+        }
+    }
+
+    fn visit_impl_item(&mut self, item: &'ast ImplItem) {
+        let attrs: &[Attribute] = match *item {
+            ImplItem::Const(ref i) => &i.attrs,
+            ImplItem::Fn(ref i) => &i.attrs,
+            ImplItem::Type(ref i) => &i.attrs,
+            ImplItem::Macro(ref i) => &i.attrs,
+            _ => &[],
+        };
+        self.with_cfgs(attrs, |this| visit::visit_impl_item(this, item));
+    }
+
+    fn visit_trait_item(&mut self, item: &'ast TraitItem) {
+        let attrs: &[Attribute] = match *item {
+            TraitItem::Const(ref i) => &i.attrs,
+            TraitItem::Fn(ref i) => &i.attrs,
+            TraitItem::Type(ref i) => &i.attrs,
+            TraitItem::Macro(ref i) => &i.attrs,
+            _ => &[],
+        };
+        self.with_cfgs(attrs, |this| visit::visit_trait_item(this, item));
+    }
+
+    fn visit_foreign_item(&mut self, item: &'ast ForeignItem) {
+        let attrs: &[Attribute] = match *item {
+            ForeignItem::Fn(ref i) => &i.attrs,
+            ForeignItem::Static(ref i) => &i.attrs,
+            ForeignItem::Type(ref i) => &i.attrs,
+            ForeignItem::Macro(ref i) => &i.attrs,
+            _ => &[],
+        };
+        self.with_cfgs(attrs, |this| visit::visit_foreign_item(this, item));
+    }
+
+    fn visit_field(&mut self, field: &'ast Field) {
+        self.with_cfgs(&field.attrs, |this| visit::visit_field(this, field));
+    }
+
+    fn visit_variant(&mut self, variant: &'ast Variant) {
+        self.with_cfgs(&variant.attrs, |this| visit::visit_variant(this, variant));
+    }
+
+    fn visit_local(&mut self, local: &'ast Local) {
+        self.with_cfgs(&local.attrs, |this| visit::visit_local(this, local));
+    }
+
+    fn visit_stmt_macro(&mut self, stmt: &'ast StmtMacro) {
+        self.with_cfgs(&stmt.attrs, |this| visit::visit_stmt_macro(this, stmt));
+    }
+
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        self.with_cfgs(expr_attrs(expr), |this| visit::visit_expr(this, expr));
+    }
+
+    fn visit_arm(&mut self, arm: &'ast Arm) {
+        self.with_cfgs(&arm.attrs, |this| visit::visit_arm(this, arm));
+    }
+
+    fn visit_field_value(&mut self, field: &'ast FieldValue) {
+        self.with_cfgs(&field.attrs, |this| visit::visit_field_value(this, field));
+    }
+
+    fn visit_signature(&mut self, sig: &'ast Signature) {
+        self.modern_syntax |= sig.asyncness.is_some();
+        visit::visit_signature(self, sig);
+    }
+
+    fn visit_expr_async(&mut self, expr: &'ast ExprAsync) {
+        self.modern_syntax = true;
+        visit::visit_expr_async(self, expr);
+    }
+
+    fn visit_expr_await(&mut self, expr: &'ast ExprAwait) {
+        self.modern_syntax = true;
+        visit::visit_expr_await(self, expr);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        self.visit_macro_tokens(mac.tokens.clone());
+        visit::visit_macro(self, mac);
+    }
+
+    fn visit_path(&mut self, path: &'ast Path) {
+        // Only multi-segment paths can refer to crates, like `regex::Regex::new`.
+        // Those beginning with uppercase names are most likely types, like `Vec::new`.
+        if path.segments.len() > 1 || path.leading_colon.is_some() {
+            let root = path.segments[0].ident.to_string();
+            if root.starts_with(|c: char| c.is_lowercase()) {
+                self.add_path_root(root);
+            }
+        }
+        visit::visit_path(self, path);
+    }
+}
+
+
+/// Attributes of an item (which may be none for the kinds of items that can't have them).
+fn item_attrs(item: &Item) -> &[Attribute] {
+    match *item {
+        Item::Const(ref i) => &i.attrs,
+        Item::Enum(ref i) => &i.attrs,
+        Item::ExternCrate(ref i) => &i.attrs,
+        Item::Fn(ref i) => &i.attrs,
+        Item::ForeignMod(ref i) => &i.attrs,
+        Item::Impl(ref i) => &i.attrs,
+        Item::Macro(ref i) => &i.attrs,
+        Item::Mod(ref i) => &i.attrs,
+        Item::Static(ref i) => &i.attrs,
+        Item::Struct(ref i) => &i.attrs,
+        Item::Trait(ref i) => &i.attrs,
+        Item::TraitAlias(ref i) => &i.attrs,
+        Item::Type(ref i) => &i.attrs,
+        Item::Union(ref i) => &i.attrs,
+        Item::Use(ref i) => &i.attrs,
+        _ => &[],
+    }
+}
+
+/// Attributes of an expression, like `#[cfg(windows)]` on a block statement.
+fn expr_attrs(expr: &Expr) -> &[Attribute] {
+    match *expr {
+        Expr::Array(ref e) => &e.attrs,
+        Expr::Assign(ref e) => &e.attrs,
+        Expr::Async(ref e) => &e.attrs,
+        Expr::Await(ref e) => &e.attrs,
+        Expr::Binary(ref e) => &e.attrs,
+        Expr::Block(ref e) => &e.attrs,
+        Expr::Break(ref e) => &e.attrs,
+        Expr::Call(ref e) => &e.attrs,
+        Expr::Cast(ref e) => &e.attrs,
+        Expr::Closure(ref e) => &e.attrs,
+        Expr::Const(ref e) => &e.attrs,
+        Expr::Continue(ref e) => &e.attrs,
+        Expr::Field(ref e) => &e.attrs,
+        Expr::ForLoop(ref e) => &e.attrs,
+        Expr::Group(ref e) => &e.attrs,
+        Expr::If(ref e) => &e.attrs,
+        Expr::Index(ref e) => &e.attrs,
+        Expr::Infer(ref e) => &e.attrs,
+        Expr::Let(ref e) => &e.attrs,
+        Expr::Lit(ref e) => &e.attrs,
+        Expr::Loop(ref e) => &e.attrs,
+        Expr::Macro(ref e) => &e.attrs,
+        Expr::Match(ref e) => &e.attrs,
+        Expr::MethodCall(ref e) => &e.attrs,
+        Expr::Paren(ref e) => &e.attrs,
+        Expr::Path(ref e) => &e.attrs,
+        Expr::Range(ref e) => &e.attrs,
+        Expr::Reference(ref e) => &e.attrs,
+        Expr::Repeat(ref e) => &e.attrs,
+        Expr::Return(ref e) => &e.attrs,
+        Expr::Struct(ref e) => &e.attrs,
+        Expr::Try(ref e) => &e.attrs,
+        Expr::TryBlock(ref e) => &e.attrs,
+        Expr::Tuple(ref e) => &e.attrs,
+        Expr::Unary(ref e) => &e.attrs,
+        Expr::Unsafe(ref e) => &e.attrs,
+        Expr::While(ref e) => &e.attrs,
+        Expr::Yield(ref e) => &e.attrs,
+        _ => &[],
+    }
+}
+
+/// Return the condition of a #[cfg(...)] attribute,
+/// or None if it's some other attribute.
+fn cfg_condition(attr: &Attribute) -> Option<String> {
+    if !attr.path().is_ident("cfg") {
+        return None;
+    }
+    attr.meta.require_list().ok().map(|list| list.tokens.to_string())
+}
+
+/// Check whether the #[cfg] condition only depends on the target platform.
+fn is_target_cfg(cfg: &str) -> bool {
+    fn check(tokens: TokenStream) -> bool {
+        tokens.into_iter().all(|tt| match tt {
+            TokenTree::Ident(ident) => {
+                let ident = ident.to_string();
+                ident.starts_with("target_") || TARGET_CFGS.contains(&ident.as_str())
+                    || ["all", "any", "not"].contains(&ident.as_str())
+            },
+            TokenTree::Group(group) => check(group.stream()),
+            TokenTree::Punct(_) | TokenTree::Literal(_) => true,
+        })
+    }
+    cfg.parse().map(check).unwrap_or(false)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, Dependency, LEGACY_EDITION, MODERN_EDITION};
+
+    fn deps(code: &str) -> Vec<Dependency> {
+        extract(vec![code]).unwrap().crates
+    }
+
+    fn dep(crate_name: &str, cfg: Option<&str>) -> Dependency {
+        Dependency{crate_name: crate_name.into(), cfg: cfg.map(str::to_owned), extern_crate: false}
+    }
+
+    #[test]
+    fn extern_crates() {
+        let crates = deps("extern crate std as stdlib;\nextern crate self as me;\n\
+                           extern crate serde_json as json;\n#[cfg(unix)] extern crate libc;\n\
+                           fn main() { json::to_string(&1); }");
+        assert_eq!(crates, vec![
+            Dependency{crate_name: "libc".into(), cfg: Some("unix".into()), extern_crate: true},
+            Dependency{crate_name: "serde_json".into(), cfg: None, extern_crate: true},
+        ]);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(extract(vec!["fn main() {"]).is_err());
+    }
+
+    #[test]
+    fn cfg_on_items_and_mods() {
+        assert_eq!(deps("#[cfg(windows)] fn f() { winapi::foo(); }\n\
+                         #[cfg(unix)] mod m { use libc::c_int; }"),
+                   vec![dep("libc", Some("unix")), dep("winapi", Some("windows"))]);
+    }
+
+    #[test]
+    fn cfg_on_impls_and_methods() {
+        assert_eq!(deps("struct Foo;\n#[cfg(windows)] impl Foo { fn f() { winapi::foo(); } }\n\
+                         impl Foo { #[cfg(unix)] fn g() { libc::getpid(); } }"),
+                   vec![dep("libc", Some("unix")), dep("winapi", Some("windows"))]);
+    }
+
+    #[test]
+    fn cfg_on_statements() {
+        let code = "fn main() {\n\
+                        #[cfg(windows)] { winreg::RegKey::predef(0); }\n\
+                        #[cfg(unix)] let _x = nix::unistd::getpid();\n\
+                        #[cfg(target_os = \"linux\")] procfs::foo!();\n\
+                    }";
+        assert_eq!(deps(code), vec![dep("nix", Some("unix")),
+                                    dep("procfs", Some("target_os = \"linux\"")),
+                                    dep("winreg", Some("windows"))]);
+    }
+
+    #[test]
+    fn nested_cfgs_are_combined() {
+        assert_eq!(deps("#[cfg(unix)] mod m { #[cfg(target_os = \"linux\")] fn f() { a::b(); } }"),
+                   vec![dep("a", Some("all(unix, target_os = \"linux\")"))]);
+    }
+
+    #[test]
+    fn unconditional_use_wins() {
+        assert_eq!(deps("#[cfg(windows)] fn f() { x::y(); }\nfn g() { x::z(); }"),
+                   vec![dep("x", None)]);
+    }
+
+    #[test]
+    fn non_target_and_test_cfgs() {
+        assert_eq!(deps("#[cfg(feature = \"fancy\")] fn f() { fancy::go(); }\n\
+                         #[cfg(test)] mod tests { use quickcheck::quickcheck; }"),
+                   vec![dep("fancy", None)]);
+    }
+
+    #[test]
+    fn use_and_path_roots() {
+        assert_eq!(deps("use regex::Regex;\nuse serde::{Serialize, de::Deserialize};\nuse rand;\n\
+                         fn main() { chrono::Utc::now(); ::log::info(); Vec::<u8>::new(); \
+                                     std::env::args(); }"),
+                   vec![dep("chrono", None), dep("log", None), dep("rand", None),
+                        dep("regex", None), dep("serde", None)]);
+    }
+
+    #[test]
+    fn local_names_arent_crates() {
+        assert_eq!(deps("mod helpers { pub fn f() {} }\nuse self::helpers as h;\n\
+                         fn main() { helpers::f(); h::f(); crate::helpers::f(); i32::max_value(); }"),
+                   vec![]);
+        // Names can also be defined in another file, like the script's module.
+        let crates = extract(vec!["mod util;\nfn main() { util::f(); utils::g(); }",
+                                  "pub fn f() {}\npub mod utils { pub fn g() {} }"])
+            .unwrap().crates;
+        assert_eq!(crates, vec![]);
+    }
+
+    #[test]
+    fn paths_in_macros() {
+        assert_eq!(deps("fn main() { println!(\"{}\", itertools::join(&[1], \",\")); \
+                                     let v = vec![x.iter().collect::<Vec<_>>()]; }\n\
+                         macro_rules! m { () => { $crate::foo::bar() }; }"),
+                   vec![dep("itertools", None)]);
+    }
+
+    #[test]
+    fn edition() {
+        assert_eq!(extract(vec!["fn main() {}"]).unwrap().edition(), MODERN_EDITION);
+        assert_eq!(extract(vec!["extern crate rand;\nfn main() { try!(rand::f()); }"])
+                       .unwrap().edition(),
+                   LEGACY_EDITION);
+        assert_eq!(extract(vec!["extern crate rand;\nfn main() { regex::f(); }"])
+                       .unwrap().edition(),
+                   MODERN_EDITION);
+        assert_eq!(extract(vec!["extern crate tokio;\nasync fn f() {}\nfn main() {}"])
+                       .unwrap().edition(),
+                   MODERN_EDITION);
+        assert_eq!(extract(vec!["extern crate tokio;\nfn main() { block_on!(f().await); }"])
+                       .unwrap().edition(),
+                   MODERN_EDITION);
+    }
+}
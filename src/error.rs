@@ -0,0 +1,86 @@
+//! Module defining the errors that can occur while running a script.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Output};
+
+use clap;
+
+use config::ConfigError;
+use manifest::ManifestError;
+use script::Source;
+use util::exitcode::{self, ExitCode};
+
+
+/// Error that can occur while running a script.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid command line arguments (or runrs flags in the script's shebang or header).
+    Args(clap::Error),
+    /// Script couldn't be loaded from its source.
+    Script(Source, io::Error),
+    /// Configuration couldn't be loaded.
+    Config(ConfigError),
+    /// I/O error on a file or directory managed by runrs (like the script workspace).
+    Io(PathBuf, io::Error),
+    /// Cargo manifest embedded in the script is malformed.
+    EmbeddedManifest(ManifestError),
+    /// Cargo manifest of the workspace or a script crate couldn't be read or updated.
+    Manifest(PathBuf, String),
+    /// External program (like Cargo) couldn't be started.
+    Spawn(String, io::Error),
+    /// External program has failed (other than when building the script).
+    Command(String, ExitStatus),
+    /// Script failed to build.
+    Build(ExitStatus),
+    /// Binary built from the script couldn't be executed.
+    Run(PathBuf, io::Error),
+    /// Script has run but failed, i.e. exited with an error.
+    /// Its output is only there if it's been captured (by `Runner::output`).
+    Failed(Output),
+}
+
+impl Error {
+    /// Exit code that the application should return because of this error.
+    pub fn exit_code(&self) -> ExitCode {
+        match *self {
+            Error::Args(_) => exitcode::EX_USAGE,
+            Error::Script(..) => exitcode::EX_NOINPUT,
+            Error::Config(_) => exitcode::EX_CONFIG,
+            Error::Io(..) => exitcode::EX_OSFILE,
+            Error::EmbeddedManifest(_) => exitcode::EX_DATAERR,
+            Error::Manifest(..) => exitcode::EX_DATAERR,
+            Error::Spawn(..) => exitcode::EX_UNAVAILABLE,
+            Error::Command(..) => exitcode::EX_UNAVAILABLE,
+            Error::Build(_) => exitcode::EX_SOFTWARE,
+            Error::Run(..) => exitcode::EX_OSERR,
+            // Propagate the same exit code that the script returned.
+            Error::Failed(ref output) => output.status.code().unwrap_or(exitcode::EX_TEMPFAIL),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Args(ref e) => write!(fmt, "invalid arguments: {}", e),
+            Error::Script(ref source, ref e) => write!(fmt, "can't read script {}: {}", source, e),
+            Error::Config(ref e) => write!(fmt, "{}", e),
+            Error::Io(ref path, ref e) => write!(fmt, "I/O error on {}: {}", path.display(), e),
+            Error::EmbeddedManifest(ref e) =>
+                write!(fmt, "invalid Cargo manifest embedded in the script: {}", e),
+            Error::Manifest(ref path, ref msg) =>
+                write!(fmt, "can't update Cargo manifest {}: {}", path.display(), msg),
+            Error::Spawn(ref cmd, ref e) => write!(fmt, "failed to run {}: {}", cmd, e),
+            Error::Command(ref cmd, ref status) => write!(fmt, "{} returned an error: {}", cmd, status),
+            Error::Build(ref status) => write!(fmt, "script failed to build: {}", status),
+            Error::Run(ref binary, ref e) =>
+                write!(fmt, "can't execute script binary {}: {}", binary.display(), e),
+            Error::Failed(ref output) => write!(fmt, "script failed: {}", output.status),
+        }
+    }
+}
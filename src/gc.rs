@@ -0,0 +1,431 @@
+//! Module implementing the garbage collection of script crates (`runrs gc`),
+//! which would otherwise keep piling up in the workspace as more scripts are ran.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use regex::Regex;
+
+use cache;
+use error::Error;
+use util;
+use workspace;
+
+
+/// Limits on the script crates that are kept around.
+/// Crates which have been used least recently are evicted first.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// Maximum time since a crate has been last used.
+    pub max_age: Option<Duration>,
+    /// Maximum number of crates.
+    pub max_count: Option<usize>,
+    /// Maximum total size of the crates (including their build artifacts), in bytes.
+    pub max_size: Option<u64>,
+}
+
+impl Policy {
+    /// Whether the policy doesn't actually impose any limits.
+    pub fn is_empty(&self) -> bool {
+        self.max_age.is_none() && self.max_count.is_none() && self.max_size.is_none()
+    }
+
+    /// Fill in the limits that haven't been specified here with those from `other`,
+    /// which has lower precedence (e.g. it comes from the config file).
+    pub fn merge(&mut self, other: &Policy) {
+        self.max_age = self.max_age.or(other.max_age);
+        self.max_count = self.max_count.or(other.max_count);
+        self.max_size = self.max_size.or(other.max_size);
+    }
+}
+
+
+/// Duration of given number of days (as max age is usually given), unless it's too long.
+pub fn days(count: u64) -> Option<Duration> {
+    const SECS_PER_DAY: u64 = 24 * 60 * 60;
+    count.checked_mul(SECS_PER_DAY).map(Duration::from_secs)
+}
+
+
+/// Script crate (or a standalone script build) that runrs keeps around.
+#[derive(Clone, Debug)]
+pub struct CachedCrate {
+    /// Directory of the crate.
+    pub dir: PathBuf,
+    /// Workspace that the crate is a member of (unless it's a standalone build).
+    pub workspace_dir: Option<PathBuf>,
+    /// Script that the crate has been last built for, if known.
+    pub script: Option<String>,
+    /// Time the crate has been last used, in seconds since Unix epoch.
+    pub last_used: u64,
+    /// Total size of the crate's directory and its build artifacts, in bytes.
+    pub size: u64,
+    /// Build artifacts of the crate within the workspace's target directory.
+    artifacts: Vec<PathBuf>,
+    /// Entries of the index of script binaries that refer to the crate.
+    index_entries: Vec<PathBuf>,
+}
+
+impl CachedCrate {
+    /// ID of the crate (which is also its directory name).
+    pub fn id(&self) -> String {
+        self.dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+}
+
+
+/// Result of garbage collection.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// Crates that have been evicted (or would be, on a dry run).
+    pub evicted: Vec<CachedCrate>,
+    /// Number of crates that have been kept.
+    pub kept: usize,
+}
+
+impl Report {
+    /// Total size of the evicted crates, in bytes.
+    pub fn freed(&self) -> u64 {
+        self.evicted.iter().map(|c| c.size).sum()
+    }
+}
+
+
+/// Evict the script crates which exceed the limits of given policy.
+///
+/// The crate in `keep` directory (like the one of the script that's just been built)
+/// is never evicted. With `dry_run`, nothing is removed but the report is the same.
+pub fn collect(policy: &Policy, dry_run: bool, keep: Option<&Path>) -> Result<Report, Error> {
+    // Script crates mustn't be created or updated while we're removing them,
+    // so all the workspaces stay locked until we're done.
+    let workspace_dirs = workspace::all_workspace_dirs();
+    let mut locks = Vec::with_capacity(workspace_dirs.len());
+    for dir in &workspace_dirs {
+        locks.push(try!(workspace::lock_workspace(dir)));
+    }
+
+    let crates = try!(find_crates(&workspace_dirs));
+    trace!("Found script crates"; "count" => crates.len());
+    let report = select(crates, policy, keep);
+    if report.evicted.is_empty() || dry_run {
+        return Ok(report);
+    }
+
+    // Crates are removed from workspace members first,
+    // so that Cargo never runs into a member that doesn't exist.
+    for workspace_dir in &workspace_dirs {
+        let ids: Vec<_> = report.evicted.iter()
+            .filter(|c| c.workspace_dir.as_ref() == Some(workspace_dir))
+            .map(|c| c.id()).collect();
+        if !ids.is_empty() {
+            try!(workspace::remove_workspace_members(workspace_dir, &ids));
+        }
+    }
+    for krate in &report.evicted {
+        debug!("Evicting script crate";
+            "dir" => krate.dir.display().to_string(), "size" => krate.size);
+        // Standalone scripts are locked while they're built, so we mustn't remove one mid-build.
+        // (Crates in workspaces are covered by the workspace locks we already hold).
+        let _lock = match krate.workspace_dir {
+            None => Some(try!(workspace::lock_workspace(&krate.dir))),
+            Some(_) => None,
+        };
+        try!(remove_dir_or_file(&krate.dir));
+        for path in krate.artifacts.iter().chain(&krate.index_entries) {
+            try!(remove_dir_or_file(path));
+        }
+    }
+    info!("Script crates evicted";
+        "count" => report.evicted.len(), "freed" => util::format_size(report.freed()));
+    Ok(report)
+}
+
+
+/// Check whether the script crates are likely to exceed the limits of given policy.
+///
+/// Unlike `collect`, this doesn't scan the workspaces (nor locks them) but only reads
+/// the index of script binaries, so it's cheap enough to do after every build.
+/// Sizes of the crates are estimated by the sizes of their binaries, which make the bulk of them.
+pub fn over_budget(policy: &Policy) -> bool {
+    if policy.is_empty() {
+        return false;
+    }
+    let mut crates: HashMap<PathBuf, (u64, u64)> = HashMap::new();
+    for entry in cache::entries() {
+        let krate = crates.entry(entry.crate_dir).or_insert((0, 0));
+        krate.0 = krate.0.max(entry.last_used);
+        krate.1 += entry.binary_size;
+    }
+
+    let now = util::unix_timestamp(SystemTime::now());
+    let min_last_used = policy.max_age.map(|age| now.saturating_sub(age.as_secs()));
+    let total_size: u64 = crates.values().map(|c| c.1).sum();
+    policy.max_count.is_some_and(|max| crates.len() > max)
+        || policy.max_size.is_some_and(|max| total_size > max)
+        || min_last_used.is_some_and(|min| crates.values().any(|c| c.0 < min))
+}
+
+
+/// Find all the script crates in given workspaces and the standalone build directories.
+fn find_crates(workspace_dirs: &[PathBuf]) -> Result<Vec<CachedCrate>, Error> {
+    // Figure out when the crates have been used, and for which scripts,
+    // from the index of script binaries.
+    let mut usage: HashMap<PathBuf, (u64, String, Vec<PathBuf>)> = HashMap::new();
+    for entry in cache::entries() {
+        let crate_usage = usage.entry(entry.crate_dir).or_insert((0, String::new(), vec![]));
+        if entry.last_used >= crate_usage.0 {
+            crate_usage.0 = entry.last_used;
+            crate_usage.1 = entry.script;
+        }
+        crate_usage.2.push(entry.path);
+    }
+
+    let mut crates = vec![];
+    let mut add_crates = |dir: &Path, workspace_dir: Option<&Path>,
+                          artifacts: &mut HashMap<String, Vec<PathBuf>>| -> io::Result<()> {
+        for crate_dir in try!(crate_dirs(dir)) {
+            let (last_used, script, index_entries) = match usage.remove(&crate_dir) {
+                Some(usage) => usage,
+                // Crates which haven't been recorded in the index (e.g. built by older versions
+                // of runrs) are assumed to be last used when they've been last modified.
+                None => (try!(last_modified(&crate_dir)), String::new(), vec![]),
+            };
+            let id = crate_dir.file_name().unwrap().to_string_lossy().into_owned();
+            let artifacts = artifacts.remove(&id).unwrap_or_default();
+            let mut size = try!(disk_usage(&crate_dir));
+            for path in &artifacts {
+                size += try!(disk_usage(path));
+            }
+            crates.push(CachedCrate{
+                dir: crate_dir,
+                workspace_dir: workspace_dir.map(Path::to_owned),
+                script: if script.is_empty() { None } else { Some(script) },
+                last_used: last_used,
+                size: size,
+                artifacts: artifacts,
+                index_entries: index_entries,
+            });
+        }
+        Ok(())
+    };
+
+    for workspace_dir in workspace_dirs {
+        let target_dir = workspace::target_dir(workspace_dir);
+        let mut artifacts = HashMap::new();
+        try!(find_artifacts(&target_dir, 0, &mut artifacts)
+            .map_err(|e| Error::Io(target_dir.clone(), e)));
+        try!(add_crates(workspace_dir, Some(workspace_dir), &mut artifacts)
+            .map_err(|e| Error::Io(workspace_dir.clone(), e)));
+    }
+    for standalone_dir in workspace::all_standalone_dirs() {
+        try!(add_crates(&standalone_dir, None, &mut HashMap::new())
+            .map_err(|e| Error::Io(standalone_dir.clone(), e)));
+    }
+    Ok(crates)
+}
+
+/// Choose the crates to evict according to given policy.
+fn select(mut crates: Vec<CachedCrate>, policy: &Policy, keep: Option<&Path>) -> Report {
+    let now = util::unix_timestamp(SystemTime::now());
+    let min_last_used = policy.max_age.map(|age| now.saturating_sub(age.as_secs()));
+
+    // Going from the most recently used crates, we keep them until we run out of the budget.
+    // The crate that must be kept counts against the budget first, as if it was used just now.
+    crates.sort_by_key(|c| (keep != Some(c.dir.as_path()), Reverse(c.last_used)));
+    let mut report = Report::default();
+    let mut kept_size = 0;
+    let mut over_budget = false;
+    for krate in crates {
+        if keep != Some(krate.dir.as_path()) {
+            over_budget = over_budget
+                || policy.max_count.is_some_and(|max| report.kept >= max)
+                || policy.max_size.is_some_and(|max| kept_size + krate.size > max);
+            let too_old = min_last_used.is_some_and(|min| krate.last_used < min);
+            if over_budget || too_old {
+                report.evicted.push(krate);
+                continue;
+            }
+        }
+        report.kept += 1;
+        kept_size += krate.size;
+    }
+    report
+}
+
+
+/// List the directories of script crates within given directory.
+fn crate_dirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    lazy_static! {
+        /// Crate directories are named after script IDs, which are SHA1 hashes.
+        static ref CRATE_ID_RE: Regex = Regex::new("^[0-9a-f]{40}$").unwrap();
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut dirs = vec![];
+    for entry in entries {
+        let entry = try!(entry);
+        let is_crate = entry.file_name().to_str().is_some_and(|n| CRATE_ID_RE.is_match(n));
+        if is_crate && try!(entry.file_type()).is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+/// Find the build artifacts of script crates within given target directory,
+/// grouping them by the crate ID.
+///
+/// Since package names of script crates contain their IDs, so do the names of their artifacts
+/// (like deps/foo_<id>-<hash>.d or .fingerprint/foo-<id>-<hash>) in every profile directory.
+fn find_artifacts(dir: &Path, depth: usize,
+                  artifacts: &mut HashMap<String, Vec<PathBuf>>) -> io::Result<()> {
+    lazy_static! {
+        static ref CRATE_ID_RE: Regex = Regex::new("[0-9a-f]{40}").unwrap();
+    }
+    /// Artifacts are found no deeper than in target/<triple>/<profile>/deps/.
+    const MAX_DEPTH: usize = 4;
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = try!(entry);
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // With $CARGO_TARGET_DIR, target directories of other toolchains' workspaces are nested
+        // within that of the default one, but their artifacts aren't this workspace's.
+        if depth == 0 && name == "toolchains" {
+            continue;
+        }
+        if let Some(m) = CRATE_ID_RE.find(&name) {
+            artifacts.entry(name[m.0..m.1].to_owned()).or_default().push(entry.path());
+        } else if depth + 1 < MAX_DEPTH && try!(entry.file_type()).is_dir() {
+            try!(find_artifacts(&entry.path(), depth + 1, artifacts));
+        }
+    }
+    Ok(())
+}
+
+/// Time of the last modification of the directory or its immediate contents,
+/// in seconds since Unix epoch.
+fn last_modified(dir: &Path) -> io::Result<u64> {
+    let mut mtime = try!(try!(fs::metadata(dir)).modified());
+    for entry in try!(fs::read_dir(dir)) {
+        if let Ok(modified) = try!(entry).metadata().and_then(|m| m.modified()) {
+            mtime = if modified > mtime { modified } else { mtime };
+        }
+    }
+    Ok(util::unix_timestamp(mtime))
+}
+
+/// Total size of given file, or all the files in given directory (recursively), in bytes.
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = try!(fs::symlink_metadata(path));
+    if !metadata.is_dir() {
+        // Cargo hard-links the binaries it builds into multiple places within target/,
+        // so they'd be counted multiple times if we didn't split their size between the links.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            return Ok(metadata.len() / metadata.nlink().max(1));
+        }
+        #[cfg(not(unix))]
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in try!(fs::read_dir(path)) {
+        size += try!(disk_usage(&try!(entry).path()));
+    }
+    Ok(size)
+}
+
+/// Remove given file or directory (with all its contents), if it exists.
+fn remove_dir_or_file(path: &Path) -> Result<(), Error> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    result.map_err(|e| Error::Io(path.to_owned(), e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    use util;
+    use super::{days, select, CachedCrate, Policy};
+
+    fn krate(name: &str, last_used: u64, size: u64) -> CachedCrate {
+        CachedCrate{
+            dir: PathBuf::from("/workspace").join(name),
+            workspace_dir: Some(PathBuf::from("/workspace")),
+            script: None,
+            last_used: last_used,
+            size: size,
+            artifacts: vec![],
+            index_entries: vec![],
+        }
+    }
+
+    fn evicted(crates: Vec<CachedCrate>, policy: &Policy, keep: Option<&str>) -> Vec<String> {
+        let keep = keep.map(|name| Path::new("/workspace").join(name));
+        let report = select(crates, policy, keep.as_deref());
+        let mut ids: Vec<_> = report.evicted.iter().map(|c| c.id()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn evicts_by_age() {
+        let now = util::unix_timestamp(SystemTime::now());
+        let crates = vec![krate("new", now, 1), krate("old", now - 10 * 24 * 60 * 60, 1)];
+        let policy = Policy{max_age: days(5), ..Policy::default()};
+        assert_eq!(evicted(crates, &policy, None), vec!["old"]);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_by_count() {
+        let crates = vec![krate("a", 3, 1), krate("b", 1, 1), krate("c", 2, 1)];
+        let policy = Policy{max_count: Some(2), ..Policy::default()};
+        assert_eq!(evicted(crates, &policy, None), vec!["b"]);
+    }
+
+    #[test]
+    fn evicts_everything_older_once_over_size_budget() {
+        // Even though "c" would still fit, it's been used less recently than "b" which didn't.
+        let crates = vec![krate("a", 3, 10), krate("b", 2, 20), krate("c", 1, 1)];
+        let policy = Policy{max_size: Some(25), ..Policy::default()};
+        assert_eq!(evicted(crates, &policy, None), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn never_evicts_kept_crate() {
+        let crates = vec![krate("a", 3, 1), krate("b", 2, 1), krate("c", 1, 1)];
+        let policy = Policy{max_count: Some(1), ..Policy::default()};
+        assert_eq!(evicted(crates, &policy, Some("c")), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn keeps_everything_without_limits() {
+        let crates = vec![krate("a", 0, 1 << 40), krate("b", 0, 1 << 40)];
+        assert!(evicted(crates, &Policy::default(), None).is_empty());
+    }
+
+    #[test]
+    fn days_overflow() {
+        assert_eq!(days(2).map(|d| d.as_secs()), Some(2 * 24 * 60 * 60));
+        assert_eq!(days(u64::MAX), None);
+    }
+}
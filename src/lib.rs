@@ -0,0 +1,66 @@
+//!
+//! runrs -- Runner for Rust "scripts"
+//!
+//! Besides the `runrs` binary, this crate can be used as a library
+//! to build and run Rust scripts from other programs, using the `Runner`.
+//!
+
+             extern crate clap;
+#[macro_use] extern crate custom_derive;
+             extern crate crypto;
+#[macro_use] extern crate enum_derive;
+             extern crate fs2;
+             extern crate isatty;
+#[macro_use] extern crate lazy_static;
+             extern crate proc_macro2;
+             extern crate regex;
+#[macro_use] extern crate slog;
+#[macro_use] extern crate slog_scope;
+             extern crate syn;
+             extern crate toml;
+             extern crate toml_edit;
+
+
+pub mod cache;
+pub(crate) mod cargo;
+pub mod config;
+mod deps;
+pub mod error;
+pub mod gc;
+mod manifest;
+mod modules;
+pub mod options;
+mod packages;
+mod runner;
+pub(crate) mod script;
+pub(crate) mod util;
+mod workspace;
+
+pub use cargo::{BuildMode, CargoOptions};
+pub use config::Config;
+pub use error::Error;
+pub use options::ScriptOptions;
+pub use runner::Runner;
+pub use script::{Script, Source};
+pub use util::exitcode::{self, ExitCode};
+
+// Helpers used by the `runrs` binary's subcommands; not meant to be used otherwise.
+#[doc(hidden)]
+pub use util::{format_size, parse_size, unix_timestamp};
+
+
+use std::env;
+use std::path::PathBuf;
+
+
+lazy_static! {
+    /// Application version, as filled out by Cargo.
+    pub static ref VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+}
+
+lazy_static! {
+    /// Main application's directory.
+    pub static ref APP_DIR: PathBuf =
+        env::home_dir().unwrap_or_else(env::temp_dir).join(".runrs");
+    // TODO: use the app_dirs crate to get this in a more portable way
+}
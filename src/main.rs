@@ -4,45 +4,23 @@
 
              extern crate clap;
              extern crate conv;
-#[macro_use] extern crate custom_derive;
-             extern crate crypto;
-#[macro_use] extern crate enum_derive;
              extern crate isatty;
-#[macro_use] extern crate lazy_static;
-             extern crate regex;
+             extern crate runrs;
 #[macro_use] extern crate slog;
 #[macro_use] extern crate slog_scope;
              extern crate slog_term;
-             extern crate toml;
 
 
 mod args;
-mod cargo;
+mod commands;
 mod logging;
-mod util;
-mod workspace;
 
 
-use std::env;
-use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
 use std::process::exit;
 
-use util::exitcode;
-
-
-lazy_static! {
-    /// Application version, as filled out by Cargo.
-    pub static ref VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
-}
-
-lazy_static! {
-    /// Main application's directory.
-    pub static ref APP_DIR: PathBuf =
-        env::home_dir().unwrap_or_else(|| env::temp_dir()).join(".runrs");
-    // TODO: use the app_dirs crate to get this in a more portable way
-}
+use runrs::{Config, Error, Runner, Script, ScriptOptions, VERSION};
+use runrs::exitcode::{self, ExitCode};
 
 
 fn main() {
@@ -54,30 +32,62 @@ fn main() {
     logging::init(opts.verbosity);
     debug!("Initializing runrs"; "version" => VERSION.unwrap_or("UNKNOWN"));
 
-    ensure_app_dir();
-    workspace::ensure_workspace();
-
-    let ref script = opts.script;
-    info!("Running script"; "path" => script.display().to_string());
-    let script_crate_dir = workspace::ensure_script_crate(script);
-
-    cargo::run(script_crate_dir, &opts.args, opts.build_mode);
+    // Configuration affects the handling of errors, so it's loaded before anything else.
+    let config = Config::load().unwrap_or_else(|e| {
+        exit(handle_error(Error::Config(e), &Config::default()));
+    });
+    let exit_code = run(opts, &config).unwrap_or_else(|e| handle_error(e, &config));
+    exit(exit_code);
 }
 
+/// Run the script as specified by the options.
+/// Returns the exit code that the application should exit with.
+fn run(mut opts: args::Options, config: &Config) -> Result<ExitCode, Error> {
+    if let Some(subcommand) = opts.subcommand.take() {
+        return commands::run(subcommand, config);
+    }
 
-/// Ensure that the application directory exists.
-fn ensure_app_dir() {
-    if APP_DIR.exists() {
-        trace!("Application directory exists, skipping creation";
-            "app_dir" => APP_DIR.display().to_string());
-        return;
+    let script = try!(Script::load(opts.script.clone())
+        .map_err(|e| Error::Script(opts.script.clone(), e)));
+
+    // The script may also specify the logging verbosity in its shebang line or header.
+    // (If it's been executed directly, the shebang flags are among the arguments already.)
+    if opts.verbosity == 0 {
+        let script_options = try!(if opts.shebang_argv { ScriptOptions::from_header(&script) }
+                                  else                 { ScriptOptions::from_script(&script) });
+        if let Some(verbosity) = script_options.verbosity {
+            logging::init(verbosity);
+        }
     }
 
-    trace!("Creating application directory"; "app_dir" => APP_DIR.display().to_string());
-    fs::create_dir_all(&*APP_DIR).unwrap_or_else(|err| {
-        error!("Failled to create application directory";
-            "app_dir" => APP_DIR.display().to_string(), "error" => format!("{}", err));
-        exit(exitcode::EX_OSFILE);
-    });
-    debug!("Application directory created"; "app_dir" => APP_DIR.display().to_string());
+    info!("Running script"; "source" => script.source.to_string());
+    let mut runner = Runner::script(script)
+        .args(opts.args)
+        .cargo_options(opts.cargo)
+        .shebang_options(!opts.shebang_argv)
+        .config(config.clone());
+    if let Some(build_mode) = opts.build_mode {
+        runner = runner.build_mode(build_mode);
+    }
+    runner.exec()
+}
+
+/// Report an error that prevented the script from running.
+/// Returns the exit code that the application should exit with.
+fn handle_error(error: Error, config: &Config) -> ExitCode {
+    match error {
+        // Usage errors contain the usage string, so they're best printed as they are.
+        Error::Args(ref e) => { write!(&mut io::stderr(), "{}", e).unwrap(); },
+        // Compiler errors have already been reported by now, so we just need to make sure
+        // that our exit code differs from any that the script itself could've returned.
+        Error::Build(ref status) => {
+            debug!("Script failed to build";
+                "status" => status.to_string(), "exit_code" => config.build_failure_exit_code);
+            return config.build_failure_exit_code;
+        },
+        // The script has already reported whatever made it fail.
+        Error::Failed(_) => {},
+        ref e => error!("Failed to run the script"; "error" => e.to_string()),
+    }
+    error.exit_code()
 }
@@ -0,0 +1,408 @@
+//! Module handling the Cargo manifests of script crates.
+
+use std::path::Path;
+
+use toml;
+use toml_edit::{self, Item, TableLike};
+
+use config::Config;
+use deps::Dependency;
+use packages;
+use script::Script;
+
+
+/// Error that can occur while parsing a manifest embedded in the script.
+pub type ManifestError = toml::ParserError;
+
+
+/// Extract the Cargo manifest embedded in the script, if any.
+///
+/// The manifest can be given in the script's frontmatter,
+/// in a `cargo` code block of its inner doc comment,
+/// or (for dependencies only) in a `// cargo-deps:` comment.
+/// If more than one is present, they are merged (in the order of increasing precedence:
+/// `// cargo-deps:`, doc comment, frontmatter).
+pub fn from_script(script: &Script) -> Result<Option<toml::Table>, ManifestError> {
+    let mut manifests = vec![
+        try!(from_cargo_deps(&script.code)),
+        try!(from_doc_comment(&script.code)),
+    ];
+    if let Some(ref frontmatter) = script.frontmatter {
+        trace!("Found Cargo manifest in script's frontmatter");
+        manifests.push(Some(try!(parse(frontmatter))));
+    }
+
+    let mut result: Option<toml::Table> = None;
+    for manifest in manifests.into_iter().flatten() {
+        match result {
+            Some(ref mut result) => merge(result, manifest),
+            None => result = Some(manifest),
+        }
+    }
+    Ok(result)
+}
+
+/// Extract dependencies given in the `// cargo-deps:` comment of the script, if any.
+///
+/// This is the format used by cargo-script (and rust-script), e.g.:
+///
+/// ```text
+/// // cargo-deps: time="0.1.25", libc="0.2", regex
+/// ```
+///
+/// Dependencies without a version are treated as wildcard (`"*"`) ones.
+fn from_cargo_deps(code: &str) -> Result<Option<toml::Table>, ManifestError> {
+    const PREFIX: &'static str = "// cargo-deps:";
+
+    let deps_lines: Vec<_> = code.lines()
+        .map(|line| line.trim())
+        .filter(|line| line.starts_with(PREFIX))
+        .map(|line| &line[PREFIX.len()..])
+        .collect();
+    if deps_lines.is_empty() {
+        return Ok(None);
+    }
+    trace!("Found `// cargo-deps:` comment(s) in the script"; "count" => deps_lines.len());
+
+    // Each of the comma-separated items is a valid TOML key-value pair,
+    // once it's given a default version if it doesn't have one.
+    let mut deps_toml = String::from("[dependencies]\n");
+    for dep in deps_lines.into_iter().flat_map(split_cargo_deps) {
+        deps_toml.push_str(dep);
+        if !dep.contains('=') {
+            deps_toml.push_str(" = \"*\"");
+        }
+        deps_toml.push('\n');
+    }
+    parse(&deps_toml).map(Some)
+}
+
+/// Split the `// cargo-deps:` list into individual items,
+/// minding the commas that may appear within version requirements (like ">=0.1, <0.3").
+fn split_cargo_deps(deps: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in deps.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                result.push(&deps[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    result.push(&deps[start..]);
+    result.into_iter().map(|dep| dep.trim()).filter(|dep| !dep.is_empty()).collect()
+}
+
+/// Extract the Cargo manifest embedded in the script's inner doc comment, if any.
+///
+/// The manifest is given as a fenced code block marked with `cargo`, e.g.:
+///
+/// ```text
+/// //! ```cargo
+/// //! [dependencies]
+/// //! time = "0.1.25"
+/// //! ```
+/// ```
+fn from_doc_comment(code: &str) -> Result<Option<toml::Table>, ManifestError> {
+    let doc_lines = code.lines()
+        .map(|line| line.trim_start())
+        .filter(|line| line.starts_with("//!"))
+        .map(|line| { let line = &line[3..]; line.strip_prefix(' ').unwrap_or(line) });
+
+    // Look for the ```cargo fence and gather everything until its closing ```.
+    let mut manifest_lines = None;
+    for line in doc_lines {
+        let line_trimmed = line.trim();
+        match manifest_lines {
+            None => if line_trimmed == "```cargo" {
+                manifest_lines = Some(vec![]);
+            },
+            Some(ref mut lines) => {
+                if line_trimmed == "```" { break }
+                lines.push(line);
+            },
+        }
+    }
+
+    match manifest_lines {
+        Some(lines) => {
+            trace!("Found Cargo manifest in script's doc comment"; "lines" => lines.len());
+            parse(&lines.join("\n")).map(Some)
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parse the TOML content of a Cargo manifest.
+fn parse(content: &str) -> Result<toml::Table, ManifestError> {
+    let mut parser = toml::Parser::new(content);
+    match parser.parse() {
+        Some(table) => Ok(table),
+        None => Err(parser.errors.remove(0)),
+    }
+}
+
+
+/// Add dependencies inferred from the script's code to the manifest.
+///
+/// The dependencies are added under their package names with a wildcard version,
+/// unless they're already specified in the manifest.
+/// Those that are gated by a #[cfg] condition become target-specific dependencies.
+pub fn add_inferred_deps<I>(manifest: &mut toml::Table, deps: I, config: &Config)
+    where I: IntoIterator<Item=Dependency>
+{
+    for dep in deps {
+        let crate_name = dep.crate_name;
+        let package = packages::resolve(&crate_name, config);
+
+        // Crate names use underscores while package names often have dashes instead.
+        // If the package is renamed, we check against the name it's been given.
+        let target_key = dep.cfg.map(|cfg| format!("cfg({})", cfg));
+        let is_specified = {
+            let mut tables = vec![manifest.get("dependencies")];
+            if let Some(ref target_key) = target_key {
+                tables.push(manifest.get("target")
+                    .and_then(|t| t.as_table()).and_then(|t| t.get(target_key))
+                    .and_then(|t| t.as_table()).and_then(|t| t.get("dependencies")));
+            }
+            tables.into_iter().filter_map(|t| t.and_then(|t| t.as_table())).any(|table| {
+                table.keys().any(|name| *name == package || name.replace("-", "_") == crate_name)
+            })
+        };
+        if is_specified {
+            trace!("Dependency already specified in the manifest"; "crate" => crate_name);
+            continue;
+        }
+
+        let deps_table = match target_key {
+            Some(ref target_key) => table_at(manifest, &["target", target_key, "dependencies"]),
+            None => table_at(manifest, &["dependencies"]),
+        };
+        let deps_table = match deps_table {
+            Some(table) => table,
+            None => {
+                warn!("Dependencies in script crate's Cargo.toml are not a table";
+                    "target" => target_key.as_deref().unwrap_or("N/A"));
+                continue;
+            },
+        };
+
+        // If the crate name can't be derived from the package name,
+        // the dependency has to be renamed so that `extern crate` can still find it.
+        let version = toml::Value::String("*".into());
+        if package.replace("-", "_") == crate_name {
+            deps_table.insert(package, version);
+        } else {
+            let mut dep_table = toml::Table::new();
+            dep_table.insert("package".into(), toml::Value::String(package));
+            dep_table.insert("version".into(), version);
+            deps_table.insert(crate_name, toml::Value::Table(dep_table));
+        }
+    }
+}
+
+/// Create a new manifest for a script crate with given package name,
+/// whose sole binary is built from given source file.
+pub fn new(package_name: &str, bin_path: &Path) -> toml::Table {
+    let mut package = toml::Table::new();
+    package.insert("name".into(), toml::Value::String(package_name.into()));
+    package.insert("version".into(), toml::Value::String("0.1.0".into()));
+    // Don't let Cargo pick up the src/main.rs that `cargo new` created.
+    package.insert("autobins".into(), toml::Value::Boolean(false));
+
+    let mut bin = toml::Table::new();
+    bin.insert("name".into(), toml::Value::String(package_name.into()));
+    bin.insert("path".into(), toml::Value::String(bin_path.to_string_lossy().into_owned()));
+
+    let mut manifest = toml::Table::new();
+    manifest.insert("package".into(), toml::Value::Table(package));
+    manifest.insert("bin".into(), toml::Value::Array(vec![toml::Value::Table(bin)]));
+    manifest.insert("dependencies".into(), toml::Value::Table(toml::Table::new()));
+    manifest
+}
+
+/// Set the Rust edition of the package described by the manifest.
+pub fn set_edition(manifest: &mut toml::Table, edition: &str) {
+    match table_at(manifest, &["package"]) {
+        Some(package) => { package.insert("edition".into(), toml::Value::String(edition.into())); },
+        None => warn!("[package] in script crate's Cargo.toml is not a table"),
+    }
+}
+
+/// Names of all the dependencies in the manifest (including target-specific ones), sorted.
+pub fn dependency_names(manifest: &toml::Table) -> Vec<String> {
+    let mut deps_tables: Vec<_> = manifest.get("dependencies").into_iter().collect();
+    if let Some(targets) = manifest.get("target").and_then(|t| t.as_table()) {
+        deps_tables.extend(targets.values().filter_map(|t| t.lookup("dependencies")));
+    }
+    let mut names: Vec<_> = deps_tables.into_iter()
+        .filter_map(|deps| deps.as_table())
+        .flat_map(|deps| deps.keys().cloned())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Get the table at given path within the manifest, creating it (and its parents) if necessary.
+/// Returns None if some value along the path isn't a table.
+fn table_at<'m>(manifest: &'m mut toml::Table, path: &[&str]) -> Option<&'m mut toml::Table> {
+    let mut table = manifest;
+    for &key in path {
+        let value = table.entry(key.to_owned())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        table = match *value {
+            toml::Value::Table(ref mut t) => t,
+            _ => return None,
+        };
+    }
+    Some(table)
+}
+
+
+/// Merge the other manifest into the first one.
+///
+/// Tables are merged recursively; any other values of `other`
+/// (including arrays) replace those in `manifest`.
+pub fn merge(manifest: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (manifest.get_mut(&key), value) {
+            (Some(&mut toml::Value::Table(ref mut table)), toml::Value::Table(other_table)) => {
+                merge(table, other_table);
+                continue;
+            },
+            (_, value) => { manifest.insert(key, value); },
+        }
+    }
+}
+
+
+/// Update a manifest document (i.e. an existing Cargo.toml) so that it matches given manifest.
+///
+/// Keys missing from the manifest are removed and those with different values are replaced,
+/// but whatever stays the same keeps its formatting and comments.
+pub fn update_document(document: &mut toml_edit::DocumentMut, manifest: &toml::Table) {
+    let new: toml_edit::DocumentMut = toml::encode_str(manifest).parse()
+        .expect("encoded manifest is not valid TOML");
+    update_table(document.as_table_mut(), new.as_table());
+}
+
+fn update_table(table: &mut toml_edit::Table, new: &toml_edit::Table) {
+    table.retain(|key, _| new.contains_key(key));
+    for (key, new_item) in new.iter() {
+        if table.get(key).is_some_and(|item| same_items(item, new_item)) {
+            continue;
+        }
+        match (table.get_mut(key), new_item) {
+            (Some(Item::Table(t)), Item::Table(new_t)) => update_table(t, new_t),
+            (Some(Item::Value(v)), Item::Value(new_v)) => {
+                // Keep any comment that follows the value.
+                let decor = v.decor().clone();
+                *v = new_v.clone();
+                *v.decor_mut() = decor;
+            },
+            (Some(item), _) => *item = new_item.clone(),
+            (None, _) => { table.insert(key, new_item.clone()); },
+        }
+    }
+}
+
+/// Whether the TOML items have the same value, regardless of their formatting
+/// (or whether their tables are inline).
+fn same_items(a: &Item, b: &Item) -> bool {
+    match (a.as_table_like(), b.as_table_like()) {
+        (Some(a), Some(b)) => return same_tables(a, b),
+        (None, None) => {},
+        _ => return false,
+    }
+    match (a, b) {
+        (Item::ArrayOfTables(a), Item::ArrayOfTables(b)) =>
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_tables(a, b)),
+        (Item::Value(a), Item::Value(b)) => same_values(a, b),
+        _ => false,
+    }
+}
+
+fn same_tables<T: TableLike + ?Sized, U: TableLike + ?Sized>(a: &T, b: &U) -> bool {
+    a.len() == b.len()
+        && a.iter().all(|(key, item)| b.get(key).is_some_and(|other| same_items(item, other)))
+}
+
+fn same_values(a: &toml_edit::Value, b: &toml_edit::Value) -> bool {
+    use toml_edit::Value::*;
+    match (a, b) {
+        (String(a), String(b)) => a.value() == b.value(),
+        (Integer(a), Integer(b)) => a.value() == b.value(),
+        (Float(a), Float(b)) => a.value() == b.value(),
+        (Boolean(a), Boolean(b)) => a.value() == b.value(),
+        (Datetime(a), Datetime(b)) => a.value() == b.value(),
+        (Array(a), Array(b)) =>
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_values(a, b)),
+        (InlineTable(a), InlineTable(b)) => same_tables(a, b),
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use toml;
+    use toml_edit;
+
+    use super::{from_cargo_deps, from_doc_comment, parse, split_cargo_deps, update_document};
+
+    #[test]
+    fn update_document_keeps_formatting_of_unchanged_parts() {
+        let content = "[package]\nname = \"x\" # the script\nversion = \"0.1.0\"\n\n\
+                       # Added by hand.\n[dependencies]\nregex = { version = \"1\" }\n\
+                       time = \"0.1\"\n";
+        let mut document: toml_edit::DocumentMut = content.parse().unwrap();
+        let manifest = toml::Parser::new("[package]\nname = \"y\"\nversion = \"0.1.0\"\n\
+                                          [dependencies]\nregex = { version = \"1\" }\n")
+            .parse().unwrap();
+
+        update_document(&mut document, &manifest);
+        assert_eq!(document.to_string(),
+                   "[package]\nname = \"y\" # the script\nversion = \"0.1.0\"\n\n\
+                    # Added by hand.\n[dependencies]\nregex = { version = \"1\" }\n");
+    }
+
+    #[test]
+    fn manifest_in_doc_comment() {
+        let code = "//! Script that tells the time.\n//!\n//! ```cargo\n//! [dependencies]\n\
+                    //!   time = \"0.1.25\"\n//! ```\n//!\n//! ```toml\n//! x = 1\n//! ```\n\
+                    fn main() {}\n";
+        assert_eq!(from_doc_comment(code).unwrap(),
+                   Some(parse("[dependencies]\ntime = \"0.1.25\"").unwrap()));
+    }
+
+    #[test]
+    fn doc_comment_without_manifest() {
+        assert_eq!(from_doc_comment("//! ```toml\n//! x = 1\n//! ```\nfn main() {}").unwrap(), None);
+        // Only inner doc comments count.
+        assert_eq!(from_doc_comment("/// ```cargo\n/// x = 1\n/// ```\nfn main() {}").unwrap(),
+                   None);
+        assert!(from_doc_comment("//! ```cargo\n//! [dependencies\n//! ```\n").is_err());
+    }
+
+    #[test]
+    fn cargo_deps_comments() {
+        let code = "// cargo-deps: time=\"0.1.25\", libc=\"0.2\",regex\n\
+                    // cargo-deps: itertools = \">=0.8, <0.11\"\nfn main() {}\n";
+        assert_eq!(from_cargo_deps(code).unwrap(),
+                   Some(parse("[dependencies]\ntime = \"0.1.25\"\nlibc = \"0.2\"\n\
+                               regex = \"*\"\nitertools = \">=0.8, <0.11\"").unwrap()));
+        assert_eq!(from_cargo_deps("// cargo-deps\nfn main() {}").unwrap(), None);
+    }
+
+    #[test]
+    fn cargo_deps_with_quoted_commas() {
+        assert_eq!(split_cargo_deps(" a=\"1, <2\" , b,, c = \"3\" "),
+                   vec!["a=\"1, <2\"", "b", "c = \"3\""]);
+        assert_eq!(split_cargo_deps(""), Vec::<&str>::new());
+    }
+}
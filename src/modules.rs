@@ -0,0 +1,284 @@
+//! Module for finding the source files of modules that a script declares with `mod foo;`.
+
+use std::path::{Path, PathBuf};
+
+use crypto::digest::Digest;
+use proc_macro2::LineColumn;
+use syn::{self, Attribute, Expr, Item, Lit, Meta};
+use syn::spanned::Spanned;
+
+use util;
+
+
+/// Source file of a module declared by the script (or by one of its other modules).
+#[derive(Clone, Debug)]
+pub struct ModuleFile {
+    /// Path to the module's file.
+    pub path: PathBuf,
+    /// Rust code of the module.
+    pub code: String,
+}
+
+
+/// Find the files of all the modules declared by a script at given path, recursively.
+pub fn find(script_path: &Path, code: &str) -> Vec<ModuleFile> {
+    let mut result = vec![];
+    // Script is the crate root, so it behaves like a mod.rs file w.r.t. module paths.
+    find_in_file(script_path, code, true, &mut result);
+    result
+}
+
+/// Find the files of modules declared in given source file, recursively.
+fn find_in_file(path: &Path, code: &str, is_mod_rs: bool, result: &mut Vec<ModuleFile>) {
+    let file = match syn::parse_file(code) {
+        Ok(file) => file,
+        Err(err) => {
+            debug!("Failed to parse a file to find its modules";
+                "path" => path.display().to_string(), "error" => format!("{}", err));
+            return;
+        },
+    };
+
+    // Modules declared in mod.rs files (and the crate root) live in the same directory,
+    // while those declared in foo.rs are in the foo/ subdirectory.
+    let file_dir = path.parent().unwrap_or(Path::new(""));
+    let mod_dir = if is_mod_rs { file_dir.to_owned() } else {
+        file_dir.join(path.file_stem().unwrap_or_default())
+    };
+    find_in_items(&file.items, &mod_dir, file_dir, result);
+}
+
+/// Find the files of modules declared among given items, recursively.
+///
+/// `mod_dir` is the directory where files of these modules would normally reside,
+/// whereas `path_base` is where #[path] attributes are relative to.
+fn find_in_items(items: &[Item], mod_dir: &Path, path_base: &Path, result: &mut Vec<ModuleFile>) {
+    for item in items {
+        let item = match *item {
+            Item::Mod(ref item) => item,
+            _ => continue,
+        };
+        let name = item.ident.to_string();
+
+        // Inline modules (`mod foo { ... }`) can declare more modules inside them.
+        if let Some((_, ref items)) = item.content {
+            let inline_dir = mod_dir.join(&name);
+            find_in_items(items, &inline_dir, &inline_dir, result);
+            continue;
+        }
+
+        let (path, is_mod_rs) = match resolve(&item.attrs, &name, mod_dir, path_base) {
+            Some(resolved) => resolved,
+            None => {
+                // This may be fine if the module is only declared for another platform.
+                debug!("File of the script's module not found";
+                    "module" => name, "dir" => mod_dir.display().to_string());
+                continue;
+            },
+        };
+        if result.iter().any(|m| m.path == path) {
+            continue;
+        }
+        let code = match util::read_text_file(&path) {
+            Ok(code) => code,
+            Err(err) => {
+                warn!("Failed to read the file of script's module";
+                    "path" => path.display().to_string(), "error" => format!("{}", err));
+                continue;
+            },
+        };
+
+        trace!("Found the file of script's module";
+            "module" => name, "path" => path.display().to_string());
+        result.push(ModuleFile{path: path.clone(), code: code.clone()});
+        find_in_file(&path, &code, is_mod_rs, result);
+    }
+}
+
+/// Find the file of a non-inline module with given name and attributes.
+/// Returns the path to the file, and whether it's treated as a mod.rs file.
+fn resolve(attrs: &[Attribute], name: &str,
+           mod_dir: &Path, path_base: &Path) -> Option<(PathBuf, bool)> {
+    // File loaded through #[path] behaves like a mod.rs file,
+    // i.e. the modules it declares live in the same directory.
+    if let Some(path) = path_attr(attrs) {
+        let path = path_base.join(path);
+        return if path.is_file() { Some((path, true)) } else { None };
+    }
+
+    let path = mod_dir.join(format!("{}.rs", name));
+    if path.is_file() {
+        return Some((path, false));
+    }
+    let path = mod_dir.join(name).join("mod.rs");
+    if path.is_file() {
+        return Some((path, true));
+    }
+    None
+}
+
+/// Return the value of the #[path = "..."] attribute, if any.
+fn path_attr(attrs: &[Attribute]) -> Option<String> {
+    attrs.iter().filter(|attr| attr.path().is_ident("path")).filter_map(|attr| {
+        match attr.meta {
+            Meta::NameValue(ref nv) => match nv.value {
+                Expr::Lit(ref expr) => match expr.lit {
+                    Lit::Str(ref s) => Some(s.value()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }).next()
+}
+
+
+/// Point the module declarations of the script at their files explicitly,
+/// by adding #[path] attributes with absolute paths to them.
+///
+/// This is necessary when the script code is compiled from a location other than its own.
+/// The attributes are added on the same lines as the declarations, so line numbers don't change.
+///
+/// A file loaded through #[path] has its own modules looked up in its directory,
+/// rather than in the subdirectory named after it (like `foo/bar.rs` for `foo.rs`).
+/// So the (non-mod.rs) module files which declare further modules are pinned the same way,
+/// and returned as copies (to be put into `copies_dir`) that the script refers to instead.
+pub fn pin_paths(script_path: &Path, code: &str, copies_dir: &Path) -> (String, Vec<ModuleFile>) {
+    // Script is the crate root, so it behaves like a mod.rs file w.r.t. module paths.
+    let script_dir = script_path.parent().unwrap_or(Path::new(""));
+    let mut copies = vec![];
+    let code = pin_in_file(code, script_dir, script_dir, copies_dir, &mut copies)
+        .unwrap_or_else(|| code.to_owned());
+    (code, copies)
+}
+
+/// Pin the paths of the modules declared in given code (see `pin_paths`).
+/// Returns None if the code doesn't declare any modules in separate files.
+fn pin_in_file(code: &str, mod_dir: &Path, path_base: &Path,
+               copies_dir: &Path, copies: &mut Vec<ModuleFile>) -> Option<String> {
+    let file = match syn::parse_file(code) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+    let mut declarations = vec![];
+    find_declarations(&file.items, mod_dir, path_base, &mut declarations);
+    if declarations.is_empty() {
+        return None;
+    }
+
+    let mut insertions = vec![];
+    for (start, path, is_mod_rs) in declarations {
+        let offset = match util::byte_offset(code, start) {
+            Some(offset) => offset,
+            None => continue,
+        };
+        let path = pin_module_file(&path, is_mod_rs, copies_dir, copies).unwrap_or(path);
+        insertions.push((offset, format!("#[path = {:?}] ", path.to_string_lossy())));
+    }
+
+    let mut result = code.to_owned();
+    for (offset, attr) in insertions.into_iter().rev() {
+        result.insert_str(offset, &attr);
+    }
+    Some(result)
+}
+
+/// Pin the paths of the modules declared in a module file, making a copy of it if needed.
+/// Returns the path to the copy, or None if the file can be used as it is.
+fn pin_module_file(path: &Path, is_mod_rs: bool,
+                   copies_dir: &Path, copies: &mut Vec<ModuleFile>) -> Option<PathBuf> {
+    // Modules of mod.rs files are in the same directory either way.
+    if is_mod_rs {
+        return None;
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let copy_path = copies_dir.join(format!("{}-{}.rs",
+        stem, &util::sha1_str(&path.to_string_lossy()).result_str()[..12]));
+    // Copies are only made once (which also keeps module cycles from recursing forever).
+    if copies.iter().any(|c| c.path == copy_path) {
+        return Some(copy_path);
+    }
+    let code = match util::read_text_file(path) {
+        Ok(code) => code,
+        Err(_) => return None,
+    };
+
+    let file_dir = path.parent().unwrap_or(Path::new(""));
+    let mod_dir = if is_mod_rs { file_dir.to_owned() } else { file_dir.join(&stem) };
+    let index = copies.len();
+    copies.push(ModuleFile{path: copy_path.clone(), code: String::new()});
+    match pin_in_file(&code, &mod_dir, file_dir, copies_dir, copies) {
+        Some(pinned) => {
+            trace!("Copying script's module with pinned paths of its own modules";
+                "path" => path.display().to_string(), "copy" => copy_path.display().to_string());
+            copies[index].code = pinned;
+            Some(copy_path)
+        },
+        None => {
+            copies.remove(index);
+            None
+        },
+    }
+}
+
+/// Find the non-inline module declarations among given items (including nested inline modules),
+/// along with their positions and the files they resolve to (see `resolve`).
+fn find_declarations(items: &[Item], mod_dir: &Path, path_base: &Path,
+                     result: &mut Vec<(LineColumn, PathBuf, bool)>) {
+    for item in items {
+        let item = match *item {
+            Item::Mod(ref item) => item,
+            _ => continue,
+        };
+        let name = item.ident.to_string();
+        if let Some((_, ref items)) = item.content {
+            let inline_dir = mod_dir.join(&name);
+            find_declarations(items, &inline_dir, &inline_dir, result);
+            continue;
+        }
+        if let Some((path, is_mod_rs)) = resolve(&item.attrs, &name, mod_dir, path_base) {
+            result.push((item.span().start(), path, is_mod_rs));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    use super::{find, pin_paths};
+
+    #[test]
+    fn nested_modules_are_pinned_through_copies() {
+        let dir = env::temp_dir().join(format!("runrs-test-modules-{}", process::id()));
+        fs::create_dir_all(dir.join("util")).unwrap();
+        fs::write(dir.join("util.rs"), "pub mod sub;\n").unwrap();
+        fs::write(dir.join("util").join("sub.rs"), "pub fn f() {}\n").unwrap();
+        fs::write(dir.join("other.rs"), "#[path = \"util/sub.rs\"] mod x;\n").unwrap();
+        let script_path = dir.join("script.rs");
+        let code = "mod util;\nmod other;\nfn main() { util::sub::f(); }\n";
+
+        let modules: Vec<_> = find(&script_path, code).into_iter().map(|m| m.path).collect();
+        assert_eq!(modules, vec![dir.join("util.rs"), dir.join("util").join("sub.rs"),
+                                 dir.join("other.rs")]);
+
+        let copies_dir = Path::new("/crate/modules");
+        let (pinned, copies) = pin_paths(&script_path, code, copies_dir);
+        assert_eq!(copies.len(), 2);
+        let util_copy = &copies[0];
+        assert!(util_copy.path.starts_with(copies_dir));
+        assert_eq!(util_copy.code,
+                   format!("#[path = {:?}] pub mod sub;\n", dir.join("util").join("sub.rs")));
+        assert_eq!(copies[1].code, format!("#[path = {:?}] #[path = \"util/sub.rs\"] mod x;\n",
+                                           dir.join("util").join("sub.rs")));
+        assert_eq!(pinned, format!("#[path = {:?}] mod util;\n#[path = {:?}] mod other;\n\
+                                    fn main() {{ util::sub::f(); }}\n",
+                                   util_copy.path, copies[1].path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+//! Module handling the runrs options that affect how scripts are built,
+//! which can be given on the command line as well as by the scripts themselves
+//! (in their shebang line and header).
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use clap::{self, AppSettings, Arg, ArgMatches, ArgSettings};
+
+use cargo::{BuildMode, CargoOptions};
+use error::Error;
+use script::Script;
+
+
+/// Options of a script's build.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScriptOptions {
+    /// Logging verbosity (the number of -v flags, or minus the number of -q flags),
+    /// if it's been given. This only matters to the `runrs` binary.
+    pub verbosity: Option<isize>,
+    /// Build mode to use (debug vs. release), if it's been given.
+    pub build_mode: Option<BuildMode>,
+    /// Options to pass to Cargo.
+    pub cargo: CargoOptions,
+}
+
+impl ScriptOptions {
+    /// Options that the script specifies in its shebang and header.
+    /// Those in the shebang take precedence.
+    pub fn from_script(script: &Script) -> Result<Self, Error> {
+        let mut options = try!(ScriptOptions::from_shebang(script));
+        options.merge(&try!(ScriptOptions::from_header(script)));
+        Ok(options)
+    }
+
+    /// Options given on the script's shebang line
+    /// (like `#!/usr/bin/env -S runrs --release`).
+    pub fn from_shebang(script: &Script) -> Result<Self, Error> {
+        let shebang = match script.shebang {
+            Some(ref shebang) => shebang,
+            None => return Ok(ScriptOptions::default()),
+        };
+        let flags = match shebang_flags(shebang) {
+            Some(flags) => flags,
+            None => {
+                trace!("Shebang doesn't refer to runrs, ignoring it"; "shebang" => shebang.as_str());
+                return Ok(ScriptOptions::default());
+            },
+        };
+        trace!("Parsing options from script's shebang"; "flags" => format!("{:?}", flags));
+        ScriptOptions::parse(&flags).map_err(|e| {
+            error!("Invalid runrs options in the script's shebang"; "shebang" => shebang.as_str());
+            Error::Args(e)
+        })
+    }
+
+    /// Options given in the script's header (see `Script::header_flags`).
+    pub fn from_header(script: &Script) -> Result<Self, Error> {
+        let flags = script.header_flags();
+        if flags.is_empty() {
+            return Ok(ScriptOptions::default());
+        }
+        trace!("Parsing options from script's header"; "flags" => format!("{:?}", flags));
+        ScriptOptions::parse(&flags).map_err(|e| {
+            error!("Invalid runrs options in the script's header"; "flags" => flags.join(" "));
+            Error::Args(e)
+        })
+    }
+
+    /// Parse options from given runrs flags (like `["--release", "--features", "foo"]`).
+    pub fn parse(flags: &[&str]) -> Result<Self, clap::Error> {
+        // Toolchain can be given as +TOOLCHAIN, like on the command line.
+        let flags: Vec<String> = flags.iter().enumerate().map(|(i, &flag)| {
+            match flag.strip_prefix('+') {
+                Some(toolchain) if i == 0 && !toolchain.is_empty() =>
+                    format!("--{}={}", OPT_TOOLCHAIN, toolchain),
+                _ => flag.to_owned(),
+            }
+        }).collect();
+        let parser = clap::App::new(APP_NAME)
+            .setting(AppSettings::NoBinaryName)
+            .setting(AppSettings::ColorNever)
+            .args(&args());
+        let matches = try!(parser.get_matches_from_safe(flags));
+        Ok(ScriptOptions::from_matches(&matches))
+    }
+
+    /// Options from the command line arguments defined by `args`.
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let verbosity = match (matches.occurrences_of(OPT_VERBOSE), matches.occurrences_of(OPT_QUIET)) {
+            (0, 0) => None,
+            (verbose, quiet) => Some(verbose as isize - quiet as isize),
+        };
+
+        let build_mode = if matches.is_present(OPT_RELEASE)    { Some(BuildMode::Release) }
+                         else if matches.is_present(OPT_DEBUG) { Some(BuildMode::Debug) }
+                         else                                  { None };
+
+        // Features can be given either as separate flags or as a comma/space-separated list.
+        let features = matches.values_of(OPT_FEATURES).into_iter().flatten()
+            .flat_map(|v| v.split(|c: char| c == ',' || c.is_whitespace()))
+            .filter(|f| !f.is_empty())
+            .map(str::to_owned)
+            .collect();
+        let cargo = CargoOptions{
+            toolchain: matches.value_of(OPT_TOOLCHAIN).map(str::to_owned),
+            features: features,
+            target: matches.value_of(OPT_TARGET).map(str::to_owned),
+            jobs: matches.value_of(OPT_JOBS).map(|j| j.parse().unwrap()),
+            offline: switch_value(matches, OPT_OFFLINE),
+            locked: switch_value(matches, OPT_LOCKED),
+            frozen: switch_value(matches, OPT_FROZEN),
+            args: matches.values_of(OPT_CARGO_ARG)
+                .map(|args| args.map(str::to_owned).collect())
+                .unwrap_or_default(),
+        };
+
+        ScriptOptions{verbosity: verbosity, build_mode: build_mode, cargo: cargo}
+    }
+
+    /// Fill in the options that haven't been specified here with those from `other`,
+    /// which has lower precedence.
+    pub fn merge(&mut self, other: &ScriptOptions) {
+        self.verbosity = self.verbosity.or(other.verbosity);
+        self.build_mode = self.build_mode.take().or_else(|| other.build_mode.clone());
+        self.cargo.merge(&other.cargo);
+    }
+}
+
+
+/// Return the runrs flags from given shebang line,
+/// or None if the shebang doesn't refer to runrs at all.
+pub fn shebang_flags(shebang: &str) -> Option<Vec<&str>> {
+    // Only the words that follow the runrs binary itself are our options.
+    // (The binary may also be preceded by /usr/bin/env and its own flags).
+    let mut words = shebang.trim_start_matches("#!").split_whitespace();
+    if !words.any(|w| Path::new(w).file_stem() == Some(OsStr::new(APP_NAME))) {
+        return None;
+    }
+    Some(words.collect())
+}
+
+
+/// Name of the runrs binary.
+const APP_NAME: &'static str = "runrs";
+
+const OPT_VERBOSE: &'static str = "verbose";
+const OPT_QUIET: &'static str = "quiet";
+const OPT_RELEASE: &'static str = "release";
+const OPT_DEBUG: &'static str = "debug";
+const OPT_TOOLCHAIN: &'static str = "toolchain";
+const OPT_FEATURES: &'static str = "features";
+const OPT_TARGET: &'static str = "target";
+const OPT_JOBS: &'static str = "jobs";
+const OPT_OFFLINE: &'static str = "offline";
+const OPT_LOCKED: &'static str = "locked";
+const OPT_FROZEN: &'static str = "frozen";
+const OPT_CARGO_ARG: &'static str = "cargo-arg";
+
+/// Long names of the options (defined by `args`) which take a value.
+pub const VALUE_OPTS: &'static [&'static str] = &[
+    OPT_TOOLCHAIN, OPT_FEATURES, OPT_TARGET, OPT_JOBS, OPT_CARGO_ARG,
+];
+
+
+/// Create the command line arguments for the options.
+/// Verbosity flags are global, so that they can also be given to subcommands.
+pub fn args<'a>() -> Vec<Arg<'a, 'a>> {
+    vec![
+        // Verbosity flags.
+        Arg::with_name(OPT_VERBOSE)
+            .long("verbose").short("v")
+            .set(ArgSettings::Multiple)
+            .global(true)
+            .conflicts_with(OPT_QUIET)
+            .help("Increase logging verbosity"),
+        Arg::with_name(OPT_QUIET)
+            .long("quiet").short("q")
+            .set(ArgSettings::Multiple)
+            .global(true)
+            .conflicts_with(OPT_VERBOSE)
+            .help("Decrease logging verbosity"),
+
+        // Build flags.
+        Arg::with_name(OPT_RELEASE)
+            .long("release")
+            .help("Build the script in release mode, with optimizations"),
+        Arg::with_name(OPT_DEBUG)
+            .long("debug")
+            .conflicts_with(OPT_RELEASE)
+            .help("Build the script in debug mode (the default), \
+                   even if its shebang or header says --release"),
+        Arg::with_name(OPT_TOOLCHAIN)
+            .long("toolchain")
+            .takes_value(true)
+            .number_of_values(1)
+            .help("Rust toolchain to build the script with, through rustup \
+                   (can also be given as +TOOLCHAIN before other arguments)")
+            .value_name("TOOLCHAIN"),
+        Arg::with_name(OPT_FEATURES)
+            .long("features")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Cargo features to enable when building the script \
+                   (comma or space-separated; may be given multiple times)")
+            .value_name("FEATURES"),
+        Arg::with_name(OPT_TARGET)
+            .long("target")
+            .takes_value(true)
+            .number_of_values(1)
+            .help("Target triple to build the script for")
+            .value_name("TRIPLE"),
+        Arg::with_name(OPT_JOBS)
+            .long("jobs").short("j")
+            .takes_value(true)
+            .number_of_values(1)
+            .validator(|v| match v.parse::<u32>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(format!("must be a positive number, got `{}`", v)),
+            })
+            .help("Number of parallel jobs Cargo should use")
+            .value_name("N"),
+        switch_arg(OPT_OFFLINE)
+            .help("Run Cargo without accessing the network"),
+        switch_arg(OPT_LOCKED)
+            .help("Require Cargo.lock to be up to date"),
+        switch_arg(OPT_FROZEN)
+            .help("Require Cargo.lock and cache to be up to date"),
+        Arg::with_name(OPT_CARGO_ARG)
+            .long("cargo-arg")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .set(ArgSettings::AllowLeadingHyphen)
+            .help("Additional argument to pass to Cargo (may be given multiple times)")
+            .value_name("ARG"),
+    ]
+}
+
+/// Create the argument for a Cargo flag like --offline, which can also be turned off explicitly
+/// (as in `--offline=false`) to override the script's header or the config.
+fn switch_arg<'a>(name: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name(name)
+        .long(name)
+        .takes_value(true)
+        .min_values(0)
+        .require_equals(true)
+        .value_name("BOOL")
+        .possible_values(&["true", "false"])
+        .hide_possible_values(true)
+}
+
+/// Value of a flag created with `switch_arg`, if it's been given.
+fn switch_value(matches: &ArgMatches, name: &str) -> Option<bool> {
+    if matches.occurrences_of(name) == 0 {
+        return None;
+    }
+    Some(matches.value_of(name) != Some("false"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cargo::BuildMode;
+    use script::{Script, Source};
+    use super::ScriptOptions;
+
+    fn script(code: &str) -> Script {
+        Script::load(Source::Eval(code.to_owned())).unwrap()
+    }
+
+    #[test]
+    fn header_flags() {
+        let options = ScriptOptions::from_script(
+            &script("// runrs: --release --offline --features x\nfn main() {}")).unwrap();
+        assert_eq!(options.build_mode, Some(BuildMode::Release));
+        assert_eq!(options.cargo.offline, Some(true));
+        assert_eq!(options.cargo.features, vec!["x".to_owned()]);
+        assert_eq!(options.verbosity, None);
+    }
+
+    #[test]
+    fn shebang_takes_precedence_over_header() {
+        let script = script("#!/usr/bin/env -S runrs +nightly -q --cargo-arg=-Zx\n\
+                             // runrs: --release -vv --toolchain stable --cargo-arg=-Zy\n\
+                             fn main() {}");
+        let options = ScriptOptions::from_script(&script).unwrap();
+        assert_eq!(options.verbosity, Some(-1));
+        assert_eq!(options.build_mode, Some(BuildMode::Release));
+        assert_eq!(options.cargo.toolchain, Some("nightly".to_owned()));
+        assert_eq!(options.cargo.args, vec!["-Zx".to_owned(), "-Zy".to_owned()]);
+
+        let options = ScriptOptions::from_header(&script).unwrap();
+        assert_eq!(options.cargo.toolchain, Some("stable".to_owned()));
+    }
+
+    #[test]
+    fn other_shebangs_are_ignored() {
+        let script = script("#!/usr/bin/env -S cargo +nightly -Zscript\nfn main() {}");
+        assert_eq!(ScriptOptions::from_script(&script).unwrap(), ScriptOptions::default());
+    }
+
+    #[test]
+    fn invalid_flags() {
+        assert!(ScriptOptions::parse(&["--bogus"]).is_err());
+        assert!(ScriptOptions::parse(&["--jobs", "0"]).is_err());
+        assert!(ScriptOptions::from_script(&script("// runrs: x.rs\nfn main() {}")).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! Module mapping crate names (as used in `extern crate`) to names of their packages.
+//!
+//! While those names are usually the same, it's not always the case:
+//! package names often use dashes where crate names have underscores,
+//! and some packages name their library crates something else entirely.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use config::Config;
+
+
+/// Built-in mapping of crate names to package names, for packages
+/// whose library crate names can't be guessed from the package name.
+const KNOWN_PACKAGES: &'static [(&'static str, &'static str)] = &[
+    ("crypto", "rust-crypto"),
+    ("ini", "rust-ini"),
+    ("lzma", "rust-lzma"),
+    ("proc_macro2", "proc-macro2"),
+    ("rustc_serialize", "rustc-serialize"),
+    ("xml", "xml-rs"),
+    ("yaml_rust", "yaml-rust"),
+];
+
+
+/// Find the name of the package that contains given crate.
+pub fn resolve(crate_name: &str, config: &Config) -> String {
+    if let Some(package) = config.packages.get(crate_name) {
+        trace!("Package name found in the configuration";
+            "crate" => crate_name, "package" => package.as_str());
+        return package.clone();
+    }
+    if let Some(&(_, package)) = KNOWN_PACKAGES.iter().find(|&&(c, _)| c == crate_name) {
+        trace!("Package name found in the built-in mapping";
+            "crate" => crate_name, "package" => package);
+        return package.to_owned();
+    }
+
+    // Otherwise it's a matter of dashes vs. underscores,
+    // and we can try to resolve that using the local copy of crates.io index.
+    if !crate_name.contains('_') {
+        return crate_name.to_owned();
+    }
+    let dashed_name = crate_name.replace("_", "-");
+    match (in_local_index(crate_name), in_local_index(&dashed_name)) {
+        (false, true) => {
+            debug!("Using dashed package name found in the local registry index";
+                "crate" => crate_name, "package" => dashed_name.as_str());
+            dashed_name
+        },
+        (true, true) => {
+            warn!("Ambiguous package name for crate, assuming it's the same as crate name";
+                "crate" => crate_name, "alternative" => dashed_name.as_str());
+            crate_name.to_owned()
+        },
+        _ => crate_name.to_owned(),
+    }
+}
+
+
+/// Check whether a package of given name can be found in any local registry index
+/// that Cargo keeps under its home directory.
+fn in_local_index(package: &str) -> bool {
+    let cargo_home = match env::var_os("CARGO_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::home_dir() {
+            Some(dir) => dir.join(".cargo"),
+            None => return false,
+        },
+    };
+    let index_dirs = match fs::read_dir(cargo_home.join("registry").join("index")) {
+        Ok(entries) => entries,
+        Err(_) => {
+            trace!("No local registry index found"; "cargo_home" => cargo_home.display().to_string());
+            return false;
+        },
+    };
+
+    // Path of the package within the index depends on the length of its name,
+    // e.g. 3/s/syn or ru/st/rustc-serialize.
+    // Package names are always ASCII, so there's no point looking for any other
+    // (and slicing it below could panic).
+    let name = package.to_lowercase();
+    if !name.is_ascii() {
+        return false;
+    }
+    let rel_path = match name.len() {
+        0 => return false,
+        1 => PathBuf::from("1").join(&name),
+        2 => PathBuf::from("2").join(&name),
+        3 => PathBuf::from("3").join(&name[..1]).join(&name),
+        _ => PathBuf::from(&name[..2]).join(&name[2..4]).join(&name),
+    };
+
+    // Cargo keeps the index entries it has seen in the .cache subdirectory
+    // (and for git-based indices, the whole index is also checked out).
+    index_dirs.filter_map(|entry| entry.ok()).any(|entry| {
+        let dir = entry.path();
+        dir.join(".cache").join(&rel_path).exists() || dir.join(&rel_path).exists()
+    })
+}
@@ -0,0 +1,272 @@
+//! Module implementing the runner of scripts,
+//! which is the interface for using runrs as a library.
+
+use std::borrow::Cow;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Output;
+use std::time::Instant;
+
+use super::APP_DIR;
+use cache;
+use cargo::{self, BuildMode, CargoOptions};
+use config::Config;
+use error::Error;
+use gc;
+use options::ScriptOptions;
+use script::{Script, Source};
+use util::exitcode::ExitCode;
+use workspace;
+
+
+/// Runner of a Rust script.
+///
+/// It's configured in a builder fashion, e.g.:
+///
+/// ```text
+/// let output = Runner::path("scripts/hello.rs").arg("world").build_mode(BuildMode::Release)
+///     .output()?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct Runner {
+    /// Where the script is loaded from.
+    source: Source,
+    /// The script itself, if it's been loaded already.
+    script: Option<Script>,
+    /// Arguments to the script.
+    args: Vec<String>,
+    /// Build mode to use (debug vs. release), unless the script specifies it.
+    build_mode: Option<BuildMode>,
+    /// Options to pass to Cargo (in addition to those from the script and the configuration).
+    cargo_options: CargoOptions,
+    /// Whether to apply the options from the script's shebang line.
+    shebang_options: bool,
+    /// Configuration to use instead of the one from the user's config file.
+    config: Option<Config>,
+}
+
+impl Runner {
+    /// Create a runner for the script from given source.
+    pub fn new(source: Source) -> Self {
+        Runner{
+            source: source,
+            script: None,
+            args: vec![],
+            build_mode: None,
+            cargo_options: CargoOptions::default(),
+            shebang_options: true,
+            config: None,
+        }
+    }
+
+    /// Create a runner for the script file at given path.
+    pub fn path<P: Into<PathBuf>>(path: P) -> Self {
+        Runner::new(Source::File(path.into()))
+    }
+
+    /// Create a runner for given Rust code.
+    ///
+    /// Like with `runrs -e`, the code is wrapped in `fn main()` unless it already has one,
+    /// and the value of its final expression (if any) is printed.
+    pub fn code<S: Into<String>>(code: S) -> Self {
+        Runner::new(Source::Eval(code.into()))
+    }
+
+    /// Create a runner for a script that's already been loaded.
+    pub fn script(script: Script) -> Self {
+        let mut runner = Runner::new(script.source.clone());
+        runner.script = Some(script);
+        runner
+    }
+}
+
+impl Runner {
+    /// Add an argument to pass to the script.
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Add arguments to pass to the script.
+    pub fn args<I, S>(mut self, args: I) -> Self
+        where I: IntoIterator<Item=S>, S: Into<String>
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the build mode (debug vs. release) of the script.
+    /// It takes precedence over the one from the script's shebang or header.
+    pub fn build_mode(mut self, mode: BuildMode) -> Self {
+        self.build_mode = Some(mode);
+        self
+    }
+
+    /// Set the options to pass to Cargo when building the script.
+    /// They take precedence over those from the script's shebang or header,
+    /// and from the configuration.
+    pub fn cargo_options(mut self, options: CargoOptions) -> Self {
+        self.cargo_options = options;
+        self
+    }
+
+    /// Set whether to apply the runrs options given on the script's shebang line
+    /// (which is the default).
+    ///
+    /// This should be turned off if they have been passed to the runner already,
+    /// e.g. because the script has been executed directly, through the shebang.
+    pub fn shebang_options(mut self, apply: bool) -> Self {
+        self.shebang_options = apply;
+        self
+    }
+
+    /// Use given configuration rather than loading it from the user's config file.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+}
+
+impl Runner {
+    /// Build the script (unless it's been built already) and return the path to its binary.
+    pub fn build(&self) -> Result<PathBuf, Error> {
+        let script = try!(self.load());
+        self.build_script(&script)
+    }
+
+    /// Build and run the script, capturing its output.
+    /// Returns the exit status of the script along with its stdout & stderr,
+    /// or `Error::Failed` (with the same) if the script has failed.
+    pub fn output(&self) -> Result<Output, Error> {
+        let script = try!(self.load());
+        let binary = try!(self.build_script(&script));
+        cargo::output(binary, &script.source.to_string(), &self.args)
+    }
+
+    /// Build and run the script with inherited standard streams.
+    ///
+    /// On Unix, this replaces the current process with the script, so it only returns on error.
+    /// Elsewhere, it waits for the script to finish and returns `Error::Failed` if it has failed.
+    pub fn exec(&self) -> Result<ExitCode, Error> {
+        let script = try!(self.load());
+        let binary = try!(self.build_script(&script));
+        cargo::run(binary, &script.source.to_string(), &self.args)
+    }
+
+    /// Load the script, unless it's been given already.
+    fn load(&self) -> Result<Cow<'_, Script>, Error> {
+        match self.script {
+            Some(ref script) => Ok(Cow::Borrowed(script)),
+            None => Script::load(self.source.clone()).map(Cow::Owned)
+                .map_err(|e| Error::Script(self.source.clone(), e)),
+        }
+    }
+
+    /// Build the script, returning the path to its binary.
+    fn build_script(&self, script: &Script) -> Result<PathBuf, Error> {
+        try!(ensure_app_dir());
+        let config = match self.config {
+            Some(ref config) => Cow::Borrowed(config),
+            None => Cow::Owned(try!(Config::load().map_err(Error::Config))),
+        };
+        let script_options = try!(self.script_options(script));
+        let build_mode = script_options.build_mode.unwrap_or_default();
+        let mut options = script_options.cargo;
+        options.merge(&config.cargo);
+
+        // If the script hasn't changed since it's been built, the binary can be used right away.
+        if let Some(binary) = cache::lookup(script, build_mode.clone(), &options) {
+            return Ok(binary);
+        }
+
+        let started = Instant::now();
+        let backend = try!(workspace::prepare_build(script, &config, &options));
+        try!(backend.build(build_mode.clone()));
+
+        let binary = backend.binary_path(build_mode.clone());
+        cache::record(script, build_mode, &options, &backend, started.elapsed());
+
+        // Building the script may have put the cached crates over the budget.
+        if config.auto_gc && gc::over_budget(&config.gc) {
+            if let Err(err) = gc::collect(&config.gc, false, Some(backend.crate_dir())) {
+                warn!("Failed to evict old script crates"; "error" => format!("{}", err));
+            }
+        }
+        Ok(binary)
+    }
+
+    /// Options of the script's build, as given to the runner and by the script itself
+    /// (in its shebang and header). Those given to the runner take precedence.
+    fn script_options(&self, script: &Script) -> Result<ScriptOptions, Error> {
+        let mut options = ScriptOptions{
+            verbosity: None,
+            build_mode: self.build_mode.clone(),
+            cargo: self.cargo_options.clone(),
+        };
+        options.merge(&try!(if self.shebang_options { ScriptOptions::from_script(script) }
+                            else                    { ScriptOptions::from_header(script) }));
+        Ok(options)
+    }
+}
+
+
+/// Ensure that the application directory exists.
+fn ensure_app_dir() -> Result<(), Error> {
+    if APP_DIR.exists() {
+        trace!("Application directory exists, skipping creation";
+            "app_dir" => APP_DIR.display().to_string());
+        return Ok(());
+    }
+
+    trace!("Creating application directory"; "app_dir" => APP_DIR.display().to_string());
+    try!(fs::create_dir_all(&*APP_DIR).map_err(|e| Error::Io(APP_DIR.clone(), e)));
+    debug!("Application directory created"; "app_dir" => APP_DIR.display().to_string());
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cargo::{BuildMode, CargoOptions};
+    use script::Script;
+    use super::Runner;
+
+    const CODE: &'static str = "#!/usr/bin/env -S runrs --features a --cargo-arg=-Zx\n\
+                                // runrs: --release --offline --toolchain nightly\n\
+                                fn main() {}";
+
+    #[test]
+    fn script_options_are_applied() {
+        let runner = Runner::code(CODE);
+        let script = runner.load().unwrap();
+        let options = runner.script_options(&script).unwrap();
+        assert_eq!(options.build_mode, Some(BuildMode::Release));
+        assert_eq!(options.cargo.features, vec!["a".to_owned()]);
+        assert_eq!(options.cargo.offline, Some(true));
+        assert_eq!(options.cargo.args, vec!["-Zx".to_owned()]);
+
+        let runner = Runner::code(CODE).shebang_options(false);
+        let options = runner.script_options(&script).unwrap();
+        assert_eq!(options.build_mode, Some(BuildMode::Release));
+        assert_eq!(options.cargo.features, Vec::<String>::new());
+        assert_eq!(options.cargo.args, Vec::<String>::new());
+    }
+
+    #[test]
+    fn explicit_options_take_precedence() {
+        let script = Script::load(Runner::code(CODE).source).unwrap();
+        let runner = Runner::script(script.clone())
+            .build_mode(BuildMode::Debug)
+            .cargo_options(CargoOptions{
+                toolchain: Some("stable".to_owned()),
+                features: vec!["b".to_owned()],
+                offline: Some(false),
+                ..CargoOptions::default()
+            });
+        let options = runner.script_options(&script).unwrap();
+        assert_eq!(options.build_mode, Some(BuildMode::Debug));
+        assert_eq!(options.cargo.toolchain, Some("stable".to_owned()));
+        assert_eq!(options.cargo.features, vec!["b".to_owned(), "a".to_owned()]);
+        assert_eq!(options.cargo.offline, Some(false));
+    }
+}
@@ -0,0 +1,354 @@
+//! Module defining the scripts we run.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::iter;
+use std::path::PathBuf;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use regex::Regex;
+use syn::{Block, Stmt};
+use syn::parse::Parser;
+use syn::spanned::Spanned;
+
+use modules::{self, ModuleFile};
+use util;
+
+
+/// Origin of the script's source code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// Script file at given path.
+    File(PathBuf),
+    /// Standard input of the application.
+    Stdin,
+    /// Inline Rust code given directly on the command line.
+    Eval(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::File(ref path) => write!(fmt, "{}", path.display()),
+            Source::Stdin => write!(fmt, "<stdin>"),
+            Source::Eval(_) => write!(fmt, "<eval>"),
+        }
+    }
+}
+
+
+/// Rust script whose code has been loaded into memory.
+#[derive(Clone, Debug)]
+pub struct Script {
+    /// Where the script has been loaded from.
+    pub source: Source,
+    /// Canonical path to the script file, if it's been loaded from one.
+    pub path: Option<PathBuf>,
+    /// Rust code of the script.
+    pub code: String,
+    /// Shebang line that the script started with, if any.
+    pub shebang: Option<String>,
+    /// Content of the Cargo manifest frontmatter (`---cargo` ... `---`), if any.
+    pub frontmatter: Option<String>,
+    /// Files of the modules that the script declares (like `mod helpers;`), recursively.
+    pub modules: Vec<ModuleFile>,
+}
+
+impl Script {
+    /// Load the script from given source.
+    pub fn load(source: Source) -> io::Result<Script> {
+        let code = match source {
+            Source::File(ref path) => try!(util::read_text_file(path)),
+            Source::Stdin => {
+                trace!("Reading script from standard input");
+                let mut code = String::new();
+                try!(io::stdin().read_to_string(&mut code));
+                code
+            },
+            Source::Eval(ref snippet) => wrap_snippet(snippet),
+        };
+        let (shebang, code) = strip_shebang(code);
+        let (frontmatter, code) = strip_frontmatter(code);
+        let path = match source {
+            Source::File(ref path) => Some(try!(fs::canonicalize(path))),
+            _ => None,
+        };
+        // Relative module paths can only be resolved for scripts that are files.
+        let modules = match path {
+            Some(ref path) => modules::find(path, &code),
+            None => vec![],
+        };
+        Ok(Script{
+            source: source,
+            path: path,
+            code: code,
+            shebang: shebang,
+            frontmatter: frontmatter,
+            modules: modules,
+        })
+    }
+
+    /// Name of the script, suitable for use as a part of the crate name.
+    pub fn name(&self) -> Option<&str> {
+        match self.source {
+            Source::File(ref path) => path.file_stem().and_then(|s| s.to_str()),
+            Source::Stdin => Some("stdin"),
+            Source::Eval(_) => Some("eval"),
+        }
+    }
+
+    /// Identifier of the script that's unique among all the scripts ran by runrs.
+    ///
+    /// Scripts loaded from files are identified by their path,
+    /// while others by the SHA of their code.
+    pub fn id(&self) -> String {
+        match self.path {
+            Some(ref path) => util::sha1_str(&path.to_string_lossy()).result_str(),
+            None => self.sha1().result_str(),
+        }
+    }
+
+    /// Compute SHA1 hash of the script's code
+    /// (including its frontmatter manifest and the files of its modules).
+    pub fn sha1(&self) -> Sha1 {
+        let mut content = String::new();
+        if let Some(ref frontmatter) = self.frontmatter {
+            content.push_str(frontmatter);
+            content.push('\n');
+        }
+        content.push_str(&self.code);
+        for module in &self.modules {
+            content.push_str(&format!("\n{}\n{}", module.path.display(), module.code));
+        }
+        util::sha1_str(&content)
+    }
+
+    /// Runrs options given in the script's header, i.e. in `// runrs: <flags>` comments
+    /// among the leading comment lines of the script (like `// runrs: --release --offline`).
+    pub fn header_flags(&self) -> Vec<&str> {
+        self.code.lines()
+            .map(str::trim)
+            .take_while(|line| line.is_empty() || line.starts_with("//"))
+            .filter_map(|line| {
+                let comment = line.trim_start_matches('/').trim_start();
+                comment.strip_prefix(HEADER_DIRECTIVE)
+            })
+            .flat_map(str::split_whitespace)
+            .collect()
+    }
+}
+
+/// Prefix of comments in the script's header that contain runrs options.
+const HEADER_DIRECTIVE: &'static str = "runrs:";
+
+
+/// Remove the shebang line (like #!/usr/bin/env runrs) from the script code, if present.
+/// Returns the shebang line (if any) and the remaining code.
+///
+/// The line is replaced with an empty one rather than removed outright,
+/// so that line numbers in compiler errors still match the original script.
+/// This way the shebang also doesn't affect the script's SHA.
+fn strip_shebang(code: String) -> (Option<String>, String) {
+    if !code.starts_with("#!") {
+        return (None, code);
+    }
+    // Rust also allows for inner attributes at the beginning of a file, e.g. #![feature(...)].
+    if code[2..].trim_start().starts_with('[') {
+        return (None, code);
+    }
+
+    let first_line_end = code.find('\n').unwrap_or(code.len());
+    let shebang = code[..first_line_end].trim_end().to_owned();
+    trace!("Removing shebang from the script"; "shebang" => shebang);
+    (Some(shebang), code[first_line_end..].to_owned())
+}
+
+
+/// Separate the Cargo manifest frontmatter from the rest of the script code, if present.
+/// Returns the frontmatter's content (if any) and the remaining code.
+///
+/// Frontmatter is the format of single-file Cargo packages (as in `cargo -Zscript`):
+///
+/// ```text
+/// ---cargo
+/// [dependencies]
+/// regex = "1"
+/// ---
+/// ```
+///
+/// It must be the first thing in the script, save for the shebang and blank lines.
+/// Like with the shebang, its lines are replaced with empty ones in the remaining code.
+fn strip_frontmatter(code: String) -> (Option<String>, String) {
+    let (frontmatter, rest) = {
+        let lines: Vec<_> = code.lines().collect();
+        let start = match lines.iter().position(|line| !line.trim().is_empty()) {
+            Some(idx) => idx,
+            None => return (None, code),
+        };
+
+        // Opening fence consists of at least three dashes and an optional "cargo" infostring.
+        let fence = lines[start].trim_end();
+        let dash_count = fence.chars().take_while(|&c| c == '-').count();
+        if dash_count < 3 {
+            return (None, code);
+        }
+        let infostring = fence[dash_count..].trim();
+        if !(infostring.is_empty() || infostring == "cargo") {
+            warn!("Unrecognized frontmatter in the script"; "infostring" => infostring);
+            return (None, code);
+        }
+
+        // Closing fence must have the same number of dashes.
+        let closing_fence = &fence[..dash_count];
+        let end = match lines[start + 1..].iter().position(|line| line.trim_end() == closing_fence) {
+            Some(idx) => start + 1 + idx,
+            None => {
+                warn!("Unterminated frontmatter in the script"; "fence" => fence);
+                return (None, code);
+            },
+        };
+        trace!("Removing frontmatter from the script"; "lines" => end - start - 1);
+
+        let frontmatter = lines[start + 1..end].join("\n");
+        let rest = iter::repeat_n("", end + 1).chain(lines[end + 1..].iter().cloned())
+            .collect::<Vec<_>>().join("\n");
+        (frontmatter, rest)
+    };
+
+    let mut rest = rest;
+    if code.ends_with('\n') {
+        rest.push('\n');
+    }
+    (Some(frontmatter), rest)
+}
+
+
+/// Turn a snippet of inline Rust code into a complete program.
+///
+/// Unless the snippet defines its own main() function, it is wrapped inside one.
+/// The value of its final expression (if any) is then printed using its Debug representation.
+fn wrap_snippet(snippet: &str) -> String {
+    lazy_static! {
+        static ref FN_MAIN_RE: Regex = Regex::new(r"\bfn\s+main\s*\(").unwrap();
+    }
+    if FN_MAIN_RE.is_match(snippet) {
+        trace!("Inline code has fn main(), using it verbatim");
+        return snippet.to_owned();
+    }
+
+    // The statements go into main() as they are, so that whatever they define
+    // lives long enough to be borrowed by the final expression (like in `let v = ...; &v`).
+    // Snippets ending with a statement evaluate to (), which we don't bother printing.
+    let snippet = snippet.trim();
+    let (stmts, expr) = match final_expr_offset(snippet) {
+        Some(offset) => (&snippet[..offset], &snippet[offset..]),
+        None => (snippet, "()"),
+    };
+    trace!("Wrapping inline code in fn main()"; "size" => snippet.len());
+    format!(concat!(
+        "fn main() {{\n",
+        "{}\n",
+        "    let __runrs_result = format!(\"{{:?}}\", {{\n",
+        "{}\n",
+        "    }});\n",
+        "    if __runrs_result != \"()\" {{\n",
+        "        println!(\"{{}}\", __runrs_result);\n",
+        "    }}\n",
+        "}}\n"), stmts, expr)
+}
+
+/// Find the final expression of a snippet of inline Rust code (i.e. the one without a semicolon),
+/// returning its byte offset within the snippet.
+///
+/// If the snippet doesn't parse, it's treated as one big expression,
+/// which leaves it to the compiler to report any errors.
+fn final_expr_offset(snippet: &str) -> Option<usize> {
+    let stmts = match Block::parse_within.parse_str(snippet) {
+        Ok(stmts) => stmts,
+        Err(_) => return Some(0),
+    };
+    let start = match stmts.last() {
+        Some(Stmt::Expr(expr, None)) => expr.span().start(),
+        Some(Stmt::Macro(mac)) if mac.semi_token.is_none() => mac.span().start(),
+        _ => return None,
+    };
+    util::byte_offset(snippet, start)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{final_expr_offset, strip_frontmatter, strip_shebang, wrap_snippet};
+
+    #[test]
+    fn shebang_is_stripped() {
+        let (shebang, code) = strip_shebang("#!/usr/bin/env runrs  \nfn main() {}\n".into());
+        assert_eq!(shebang, Some("#!/usr/bin/env runrs".to_owned()));
+        // The line is kept (empty), so that line numbers don't change.
+        assert_eq!(code, "\nfn main() {}\n");
+
+        let (shebang, code) = strip_shebang("#!/usr/bin/runrs".into());
+        assert_eq!(shebang, Some("#!/usr/bin/runrs".to_owned()));
+        assert_eq!(code, "");
+    }
+
+    #[test]
+    fn inner_attributes_arent_shebangs() {
+        for code in &["#![allow(dead_code)]\nfn main() {}", "#! [allow(dead_code)]\nfn main() {}",
+                      "fn main() {}\n#!/usr/bin/env runrs"] {
+            assert_eq!(strip_shebang(code.to_string()), (None, code.to_string()));
+        }
+    }
+
+    #[test]
+    fn frontmatter_is_stripped() {
+        let code = "\n---cargo\n[dependencies]\nitoa = \"1\"\n---\nfn main() {}\n";
+        let (frontmatter, rest) = strip_frontmatter(code.into());
+        assert_eq!(frontmatter, Some("[dependencies]\nitoa = \"1\"".to_owned()));
+        // Lines of the frontmatter are kept (empty), so that line numbers don't change.
+        assert_eq!(rest, "\n\n\n\n\nfn main() {}\n");
+        assert_eq!(rest.lines().count(), code.lines().count());
+
+        let (frontmatter, rest) = strip_frontmatter("----\n---\n----\nfn main() {}".into());
+        assert_eq!(frontmatter, Some("---".to_owned()));
+        assert_eq!(rest, "\n\n\nfn main() {}");
+    }
+
+    #[test]
+    fn code_without_frontmatter() {
+        for code in &["fn main() {}\n", "", "// ---\nfn main() {}",
+                      "--- python\nx = 1\n---\n", "---\n[dependencies]\n"] {
+            assert_eq!(strip_frontmatter(code.to_string()), (None, code.to_string()));
+        }
+    }
+
+    #[test]
+    fn final_expression_of_snippet() {
+        let snippet = "let v = vec![1];\n&v";
+        assert_eq!(final_expr_offset(snippet).map(|i| &snippet[i..]), Some("&v"));
+        let snippet = "let s = \"ż\"; format!(\"{}\", s)";
+        assert_eq!(final_expr_offset(snippet).map(|i| &snippet[i..]), Some("format!(\"{}\", s)"));
+        assert_eq!(final_expr_offset("2 + 2"), Some(0));
+        assert_eq!(final_expr_offset("println!(\"hi\");"), None);
+        assert_eq!(final_expr_offset("fn f() {}"), None);
+        assert_eq!(final_expr_offset(""), None);
+        // Snippets that don't parse are left to the compiler.
+        assert_eq!(final_expr_offset("let x = ;"), Some(0));
+    }
+
+    #[test]
+    fn snippet_statements_are_kept_in_main() {
+        let code = wrap_snippet("let v = vec![1]; &v");
+        assert!(code.starts_with("fn main() {\nlet v = vec![1]; \n"));
+        assert!(code.contains("format!(\"{:?}\", {\n&v\n    });"));
+
+        let code = wrap_snippet("println!(\"hi\");");
+        assert!(code.starts_with("fn main() {\nprintln!(\"hi\");\n"));
+        assert!(code.contains("format!(\"{:?}\", {\n()\n    });"));
+
+        let code = "fn main() { println!(\"hi\"); }";
+        assert_eq!(wrap_snippet(code), code);
+    }
+}
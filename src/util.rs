@@ -1,11 +1,14 @@
 //! Utility module.
 
-use std::fs::File;
-use std::io::{self, Read};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
 use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use proc_macro2::LineColumn;
 
 
 /// Reads the contents of the file into a String.
@@ -30,36 +33,99 @@ pub fn read_text_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
     Ok(content)
 }
 
-
-/// Compute SHA1 hash of the contents of given file.
-pub fn sha1_file<P: AsRef<Path>>(path: P) -> io::Result<Sha1> {
+/// Write given content into the file, replacing it atomically.
+///
+/// The content is written to a temporary file in the same directory first,
+/// which is then renamed over the target. This way, readers (possibly in other processes)
+/// see either the old content of the file or the new one, but never anything in between.
+pub fn write_file_atomic<P: AsRef<Path>>(path: P, content: &[u8]) -> io::Result<()> {
     let path = path.as_ref();
+    let file_name = try!(path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "path has no file name")
+    }));
+    let mut temp_name = file_name.to_owned();
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    trace!("Writing file atomically";
+        "path" => path.display().to_string(), "size" => content.len());
+    let result = File::create(&temp_path)
+        .and_then(|mut fp| fp.write_all(content))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+
+/// Compute SHA1 hash of given string.
+pub fn sha1_str(s: &str) -> Sha1 {
+    let mut sha = Sha1::new();
+    sha.input_str(s);
+
+    trace!("SHA1 of a string"; "size" => s.len(), "sha" => sha.result_str());
+    sha
+}
 
-    let mut file = try!(File::open(path));
-    let mut sha = try!(digest(Sha1::new(), &mut file));
 
-    trace!("SHA1 of a file";
-        "path" => path.display().to_string(),
-        "size" => file.metadata().map(|m| m.len().to_string()).unwrap_or("N/A".into()),
-        "sha" => sha.result_str());
-    Ok(sha)
+/// Convert a position in given code, as the lines & columns (in chars) that syn reports,
+/// into its byte offset. Returns None if the code doesn't have that many lines.
+pub fn byte_offset(code: &str, pos: LineColumn) -> Option<usize> {
+    let line_start = match pos.line {
+        0 => return None,
+        1 => 0,
+        line => match code.match_indices('\n').nth(line - 2) {
+            Some((i, _)) => i + 1,
+            None => return None,
+        },
+    };
+    Some(code[line_start..].char_indices().nth(pos.column)
+        .map(|(i, _)| line_start + i).unwrap_or(code.len()))
+}
+
+
+/// Convert given time to the number of seconds since Unix epoch
+/// (or zero if it's before that).
+pub fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
-/// Compute one of the crypto crate's digest for bytes read from given input.
-/// The input is read until the end.
-fn digest<D: Digest, R: Read>(digest: D, mut input: R) -> io::Result<D> {
-    // TODO: make a PR to rust-crypto to include Default impl for all digests,
-    // so that we can accept it as a generic type argument
-    let mut digest = digest;
-
-    const BUF_SIZE: usize = 256;
-    let mut buf = [0; BUF_SIZE];
-    loop {
-        let c = try!(input.read(&mut buf));
-        digest.input(&buf[0..c]);
-        if c < BUF_SIZE { break }
+/// Format given number of bytes as a human-readable size, like "12.3 MiB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &'static [&'static str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
-    Ok(digest)
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Parse a human-readable size, like "500M" or "2GiB", into the number of bytes.
+/// Units are binary (i.e. "1K" is 1024 bytes) and a plain number is in bytes.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let digits_end = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let number: f64 = match s[..digits_end].parse() {
+        Ok(n) => n,
+        Err(_) => return None,
+    };
+    let exponent = match s[digits_end..].trim().to_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 1,
+        "M" | "MB" | "MIB" => 2,
+        "G" | "GB" | "GIB" => 3,
+        "T" | "TB" | "TIB" => 4,
+        _ => return None,
+    };
+    // Sizes that don't fit in u64 would otherwise be silently clamped to its maximum.
+    let bytes = number * 1024f64.powi(exponent);
+    if bytes < u64::MAX as f64 { Some(bytes as u64) } else { None }
 }
 
 
@@ -72,9 +138,68 @@ pub mod exitcode {
 
     pub const EX_OK: ExitCode = 0;
     pub const EX_USAGE: ExitCode = 64;
+    pub const EX_DATAERR: ExitCode = 65;
     pub const EX_NOINPUT: ExitCode = 66;
     pub const EX_UNAVAILABLE: ExitCode = 69;
+    pub const EX_SOFTWARE: ExitCode = 70;
+    pub const EX_OSERR: ExitCode = 71;
     pub const EX_OSFILE: ExitCode = 72;
     pub const EX_IOERR: ExitCode = 74;
     pub const EX_TEMPFAIL: ExitCode = 75;
+    pub const EX_CONFIG: ExitCode = 78;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{format_size, parse_size, read_text_file, write_file_atomic};
+
+    #[test]
+    fn write_file_atomic_replaces_content() {
+        let dir = env::temp_dir().join(format!("runrs-test-write-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Cargo.toml");
+
+        write_file_atomic(&path, b"a much longer content").unwrap();
+        write_file_atomic(&path, b"short").unwrap();
+        assert_eq!(read_text_file(&path).unwrap(), "short");
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert!(write_file_atomic(dir.join("missing").join("file"), b"x").is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("123"), Some(123));
+        assert_eq!(parse_size("10B"), Some(10));
+        assert_eq!(parse_size("1.5K"), Some(1536));
+        assert_eq!(parse_size("500M"), Some(500 * 1024 * 1024));
+        assert_eq!(parse_size("2GiB"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size(" 3 tb "), Some(3 << 40));
+    }
+
+    #[test]
+    fn parse_size_invalid() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("12X"), None);
+        assert_eq!(parse_size("-5M"), None);
+        assert_eq!(parse_size("100000000T"), None);
+    }
+
+    #[test]
+    fn format_size_units() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        assert_eq!(format_size(2048 << 40), "2048.0 TiB");
+    }
 }
@@ -1,18 +1,28 @@
 //! Module for managing the shared Cargo workspace used by scripts we run.
 
-use std::borrow::Cow;
+use std::collections::HashSet;
+use std::env;
 use std::fs;
 use std::io::{self, Write};
+use std::iter;
 use std::path::{Path, PathBuf};
-use std::process::{Command, exit};
+use std::process;
 
 use crypto::digest::Digest;
+use fs2::FileExt;
 use isatty;
-use regex::Regex;
 use toml;
+use toml_edit;
 
 use super::APP_DIR;
-use util::{self, exitcode};
+use cargo::{Backend, Cargo, CargoOptions};
+use config::Config;
+use deps::{self, Dependency, ScriptDeps};
+use error::Error;
+use manifest;
+use modules;
+use script::Script;
+use util;
 
 
 lazy_static! {
@@ -20,190 +30,501 @@ lazy_static! {
     ///
     /// Cargo.toml here will have the [workspace] section containing paths
     /// to previously ran scripts.
+    /// Scripts built with non-default toolchains have their own workspaces within.
     pub static ref WORKSPACE_DIR: PathBuf = APP_DIR.join("workspace");
+
+    /// Directory where scripts without dependencies are built with rustc alone,
+    /// outside of the Cargo workspace.
+    pub static ref STANDALONE_DIR: PathBuf = APP_DIR.join("standalone");
+
+    /// Directory where script crates are created before they're moved into a workspace.
+    static ref STAGING_DIR: PathBuf = APP_DIR.join("staging");
+}
+
+
+/// Directory of the Cargo workspace for scripts built with given toolchain.
+///
+/// Each non-default toolchain gets a separate workspace (with its own target directory),
+/// so that artifacts built by different compilers don't keep invalidating each other.
+pub fn workspace_dir(toolchain: Option<&str>) -> PathBuf {
+    toolchain_dir(&WORKSPACE_DIR, toolchain)
+}
+
+/// Directory where Cargo puts the build artifacts of all script crates in given workspace.
+pub fn target_dir(workspace_dir: &Path) -> PathBuf {
+    // Relative $CARGO_TARGET_DIR is resolved against the current directory, as the user meant it.
+    // Workspaces of non-default toolchains still get their own subdirectories within it.
+    match env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) => {
+            let dir = env::current_dir().map(|cwd| cwd.join(&dir)).unwrap_or_else(|_| dir.into());
+            match workspace_dir.strip_prefix(&*WORKSPACE_DIR) {
+                Ok(toolchain_subdir) => dir.join(toolchain_subdir),
+                Err(_) => dir,
+            }
+        },
+        None => workspace_dir.join("target"),
+    }
+}
+
+/// Directories of all the existing Cargo workspaces (for the default toolchain and others).
+pub fn all_workspace_dirs() -> Vec<PathBuf> {
+    let mut dirs = all_toolchain_dirs(&WORKSPACE_DIR);
+    dirs.retain(|dir| dir.join("Cargo.toml").exists());
+    dirs
+}
+
+/// Directories where the standalone scripts are built (for the default toolchain and others).
+pub fn all_standalone_dirs() -> Vec<PathBuf> {
+    all_toolchain_dirs(&STANDALONE_DIR)
+}
+
+/// Subdirectory of given directory that's dedicated to given toolchain
+/// (or the directory itself for the default toolchain).
+fn toolchain_dir(dir: &Path, toolchain: Option<&str>) -> PathBuf {
+    match toolchain {
+        // Toolchain names are safe as directory names, save for possible path separators
+        // in custom toolchains, which wouldn't be found by rustup anyway.
+        Some(toolchain) =>
+            dir.join("toolchains").join(toolchain.replace(['/', '\\'], "_")),
+        None => dir.to_owned(),
+    }
+}
+
+/// Given directory along with all its existing toolchain-specific subdirectories.
+fn all_toolchain_dirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![dir.to_owned()];
+    if let Ok(entries) = fs::read_dir(dir.join("toolchains")) {
+        let mut toolchain_dirs: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.path())
+            .filter(|path| path.is_dir()).collect();
+        toolchain_dirs.sort();
+        dirs.extend(toolchain_dirs);
+    }
+    dirs
+}
+
+
+/// Prepare everything that's needed to build given Rust script,
+/// and choose the backend that will build it.
+pub fn prepare_build(script: &Script, config: &Config,
+                     options: &CargoOptions) -> Result<Backend, Error> {
+    // Check the Cargo manifest that may be embedded in the script first,
+    // so that we don't leave a half-initialized crate behind if it's malformed.
+    let embedded_manifest = try!(manifest::from_script(script).map_err(Error::EmbeddedManifest));
+
+    // Extract the script's dependencies from the `extern crate` declarations and paths.
+    // Modules of the script are taken into account too, as they may use other crates.
+    let sources = Some(script.code.as_str()).into_iter()
+        .chain(script.modules.iter().map(|m| m.code.as_str()));
+    let deps = deps::extract(sources).unwrap_or_else(|err| {
+        // Let the compiler report the syntax error in a more helpful way.
+        warn!("Failed to parse the script to find its dependencies";
+            "script" => script.source.to_string(), "error" => format!("{}", err));
+        ScriptDeps::default()
+    });
+    trace!("Extracted dependencies of the script";
+        "script" => script.source.to_string(), "deps" => format!("{:?}", deps.crates));
+
+    // The edition is also inferred from the script (unless the embedded manifest specifies it),
+    // as scripts that refer to crates without `extern crate` need the 2018 edition at least.
+    let edition = deps.edition();
+    let deps = deps.crates;
+    trace!("Inferred the edition of the script";
+        "script" => script.source.to_string(), "edition" => edition);
+
+    // Scripts that only use the standard library can be compiled with rustc directly,
+    // which is much faster than setting up a crate for them and going through Cargo.
+    // (Unless the user wants to pass some options to Cargo that rustc wouldn't understand).
+    let needs_cargo = !options.features.is_empty() || !options.args.is_empty();
+    if embedded_manifest.is_none() && deps.is_empty() && !needs_cargo {
+        debug!("Script has no dependencies, building it with rustc";
+            "script" => script.source.to_string());
+        return prepare_standalone_script(script, edition, options);
+    }
+
+    // Other runrs processes may be preparing their scripts in the same workspace concurrently,
+    // so it stays locked until the script crate is ready (the build itself is guarded by Cargo).
+    let workspace_dir = workspace_dir(options.toolchain.as_deref());
+    let _lock = try!(lock_workspace(&workspace_dir));
+    try!(ensure_workspace(&workspace_dir));
+    ensure_script_crate(&workspace_dir, script, embedded_manifest, deps, edition, config, options)
 }
 
 
-// TODO: make the functions here result a Result rather than exiting on errors
+/// Name of the package (and binary) of given script.
+fn package_name(script: &Script) -> String {
+    // Package name must be unique across the workspace,
+    // so we'll use the script's ID in it as well.
+    let id = script.id();
+    match script.name() {
+        Some(stem) => format!("{}-{}", stem, id),
+        None => id,
+    }
+}
+
+/// Prepare the build of a script without dependencies with rustc alone.
+fn prepare_standalone_script(script: &Script, edition: &'static str,
+                             options: &CargoOptions) -> Result<Backend, Error> {
+    let toolchain = options.toolchain.as_deref();
+    let out_dir = toolchain_dir(&STANDALONE_DIR, toolchain).join(script.id());
+
+    // Like with script crates, the script is compiled from where it is if possible.
+    // Otherwise its code is written into the output directory right before the build.
+    let (source, files) = match script.path {
+        Some(ref path) if script.frontmatter.is_none() => (path.clone(), vec![]),
+        _ => {
+            let source = out_dir.join("main.rs");
+            let files = script_files(script, &source);
+            (source, files)
+        },
+    };
+    Ok(Backend::Rustc{
+        source: source,
+        files: files,
+        edition: edition,
+        out_dir: out_dir,
+        bin_name: package_name(script),
+        target: options.target.clone(),
+        toolchain: options.toolchain.clone(),
+    })
+}
 
 
-/// Ensure that the root Cargo workspace exists.
+/// Take an exclusive lock on the workspace in given directory (creating the directory if needed).
+/// The lock is held until the returned file is dropped.
 ///
-/// All the scripts being executed are crates under that workspace
+/// This serializes the changes that runrs processes make to the workspace,
+/// like adding members to its root Cargo.toml and creating script crates.
+/// Output directories of standalone scripts are locked the same way while they're built.
+pub fn lock_workspace(workspace_dir: &Path) -> Result<fs::File, Error> {
+    let lock_path = workspace_dir.join(WORKSPACE_LOCK_FILE);
+    loop {
+        try!(fs::create_dir_all(workspace_dir)
+            .map_err(|e| Error::Io(workspace_dir.to_owned(), e)));
+        let lock_file = try!(fs::OpenOptions::new()
+            .write(true).create(true).truncate(false).open(&lock_path)
+            .map_err(|e| Error::Io(lock_path.clone(), e)));
+        if lock_file.try_lock_exclusive().is_err() {
+            debug!("Workspace is locked by another process, waiting";
+                "dir" => workspace_dir.display().to_string());
+            try!(lock_file.lock_exclusive().map_err(|e| Error::Io(lock_path.clone(), e)));
+        }
+
+        // The directory may have been removed by the process we were waiting for
+        // (like `runrs gc` evicting a standalone script), along with the file we've locked.
+        if is_same_file(&lock_file, &lock_path) {
+            trace!("Workspace locked"; "dir" => workspace_dir.display().to_string());
+            return Ok(lock_file);
+        }
+        debug!("Locked directory has been removed, locking it again";
+            "dir" => workspace_dir.display().to_string());
+    }
+}
+
+/// Check whether the open file is still the one at given path.
+fn is_same_file(file: &fs::File, path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (file.metadata(), fs::metadata(path)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        path.exists()
+    }
+}
+
+/// Name of the file within the workspace directory that's used for locking the workspace.
+const WORKSPACE_LOCK_FILE: &'static str = ".lock";
+
+
+/// Ensure that the root Cargo workspace in given directory exists.
+/// The workspace should be locked (see `lock_workspace`).
+///
+/// All the scripts being executed (with the same toolchain) are crates under that workspace
 /// and share the same Cargo.lock. This prevents from rebuiding shared dependencies
 /// repeatedly, thus massively speeding up the execution of scripts.
-pub fn ensure_workspace() {
-    let cargo_toml = WORKSPACE_DIR.join("Cargo.toml");
+pub fn ensure_workspace(workspace_dir: &Path) -> Result<(), Error> {
+    let cargo_toml = workspace_dir.join("Cargo.toml");
     if cargo_toml.exists() {
         trace!("Script workspace exists, skipping creation";
-            "dir" => WORKSPACE_DIR.display().to_string());
-        return;
+            "dir" => workspace_dir.display().to_string());
+        return Ok(());
     }
-
-    if WORKSPACE_DIR.exists() {
-        warn!("Script workspace directory found without Cargo.toml inside";
-            "dir" => WORKSPACE_DIR.display().to_string());
-    } else {
-        fs::create_dir_all(&*WORKSPACE_DIR).unwrap_or_else(|err| {
-            error!("Failed to create script workspace directory";
-                "dir" => WORKSPACE_DIR.display().to_string(), "error" => format!("{}", err));
-            exit(exitcode::EX_OSFILE);
-        });
-    }
-
-    let mut cargo_toml_fp = fs::OpenOptions::new()
-        .write(true).create_new(true)
-        .open(cargo_toml.clone()).unwrap_or_else(|err| {
-            error!("Failed to open Cargo.toml of script workspace";
-                "path" => cargo_toml.display().to_string(), "error" => format!("{}", err));
-            exit(exitcode::EX_OSFILE);
-        });
+    try!(fs::create_dir_all(workspace_dir).map_err(|e| Error::Io(workspace_dir.to_owned(), e)));
 
     // This initial content of Cargo.toml will be modified whenever a new script crate is added,
     // by adding the crate's relative path (SHA) to [workspace.members].
-    writeln!(&mut cargo_toml_fp, "[workspace]\nmembers = []").unwrap();
+    fs::OpenOptions::new().write(true).create_new(true).open(&cargo_toml)
+        .and_then(|mut fp| writeln!(&mut fp, "[workspace]\nmembers = []"))
+        .map_err(|e| Error::Io(cargo_toml, e))
 }
 
 
-/// Ensure that a crate for given Rust script exists within the workspace.
-/// Returns the path to the crate's directory.
-pub fn ensure_script_crate<P: AsRef<Path>>(path: P) -> PathBuf {
-    // TODO: split this function
-
-    let path = path.as_ref();
-
-    // TODO: if there is a shebang in the script (like #!/usr/bin/runrs), exclude
-    // it from SHA-ing and do not carry it over when copying the script file to
-    // its crate
-    let sha_hex = util::sha1_file(path).unwrap_or_else(|err| {
-        error!("Failed to compute SHA of the script";
-            "path" => path.display().to_string(), "error" => format!("{}", err));
-        exit(exitcode::EX_OSFILE);
-    }).result_str();
-
+/// Ensure that an up-to-date crate for given Rust script exists within given workspace.
+fn ensure_script_crate(workspace_dir: &Path, script: &Script,
+                       embedded_manifest: Option<toml::Table>, deps: Vec<Dependency>,
+                       edition: &str, config: &Config,
+                       options: &CargoOptions) -> Result<Backend, Error> {
+    // Scripts loaded from files are identified by their path, so that editing the script
+    // only updates its existing crate (which then just needs an incremental rebuild).
     // TODO: shard by 2-char prefix, like Git blobs
-    let crate_dir = WORKSPACE_DIR.join(sha_hex.clone());
-    let cargo_toml = crate_dir.join("Cargo.toml");
-    if cargo_toml.exists() {
-        trace!("Script crate already exists, skipping creation";
-            "script" => path.display().to_string(), "sha" => sha_hex);
-        return crate_dir;
+    let crate_id = script.id();
+    let crate_dir = workspace_dir.join(&crate_id);
+    let package_name = package_name(script);
+    let backend = Backend::Cargo{
+        crate_dir: crate_dir.clone(),
+        target_dir: target_dir(workspace_dir),
+        bin_name: package_name.clone(),
+        options: options.clone(),
+    };
+
+    // The SHA of script's code is then used to tell whether the crate needs updating.
+    // Note that the code doesn't include the shebang line (if any),
+    // so it's not taken into account here, nor is it copied to the crate.
+    let sha_hex = script.sha1().result_str();
+    let sha_file = crate_dir.join(SCRIPT_SHA_FILE);
+    if let Ok(crate_sha) = util::read_text_file(&sha_file) {
+        if crate_sha.trim() == sha_hex {
+            trace!("Script crate is up to date, skipping update";
+                "script" => script.source.to_string(), "crate_id" => crate_id, "sha" => sha_hex);
+            // Its membership in the workspace may still be missing, e.g. if `runrs gc`
+            // has failed to remove the crate after removing it from the members.
+            try!(add_workspace_member(workspace_dir, &crate_id));
+            return Ok(backend);
+        }
     }
 
-    if crate_dir.exists() {
+    if crate_dir.join("Cargo.toml").exists() {
+        trace!("Script crate already exists, skipping creation";
+            "script" => script.source.to_string(), "crate_id" => crate_id);
+    } else if crate_dir.exists() {
         warn!("Script crate directory found without Cargo.toml inside";
             "dir" => crate_dir.display().to_string());
     } else {
         debug!("Initializing the script crate";
-            "script" => path.display().to_string(), "sha" => sha_hex);
-
-        // Add the new script crate path to [workspace.members] of the root Cargo.toml.
-        // Since this root is "virtual" (i.e. doesn't correspond to any crate on its own),
-        // this is the only way to define the workspace.
-        //
-        // Note that we do this before actually creating the script crate via `cargo new`
-        // because it prevents Cargo from emitting a warning about workspace misconfiguration.
-        trace!("Fixing root Cargo.toml to point to the script crate";
-            "crate_dir" => crate_dir.display().to_string());
-        {
-            let root_cargo_toml = WORKSPACE_DIR.join("Cargo.toml");
-            let content = util::read_text_file(&root_cargo_toml).unwrap();
-
-            let mut root: toml::Value = content.parse().unwrap();
-            {
-                let ws_members = root.lookup_mut("workspace.members").unwrap();
-                let mut ws_members_vec: Vec<_> = ws_members.as_slice().unwrap().to_owned();
-                ws_members_vec.push(toml::Value::String(sha_hex.clone()));
-                *ws_members = toml::Value::Array(ws_members_vec);
-                // TODO: prevent duplicates
-            }
+            "script" => script.source.to_string(), "crate_id" => crate_id);
+        try!(create_script_crate(workspace_dir, &crate_id, &package_name, options));
+        debug!("Script crate initialized successfully";
+            "script" => script.source.to_string(), "crate_id" => crate_id);
+    }
 
-            let mut fp = fs::OpenOptions::new().write(true).open(&root_cargo_toml).unwrap();
-            write!(&mut fp, "{}", toml::encode_str(&root)).unwrap();
-        }
+    debug!("Updating the script crate";
+        "script" => script.source.to_string(), "crate_id" => crate_id, "sha" => sha_hex);
+    try!(update_script_crate(
+        &crate_dir, &package_name, script, embedded_manifest, deps, edition, config));
+    try!(add_workspace_member(workspace_dir, &crate_id));
+    try!(util::write_file_atomic(&sha_file, sha_hex.as_bytes())
+        .map_err(|e| Error::Io(sha_file, e)));
 
-        // Run `cargo new --bin $SCRIPT_SHA` in the workspace directory
-        // to actually create the script crate.
-        let package_name: Cow<str> = match path.file_stem().and_then(|s| s.to_str()) {
-            // Package name must be unique across the workspace,
-            // so we'll use the SHA in it as well.
-            Some(stem) => Cow::Owned(format!("{}-{}", stem, sha_hex)),
-            None => Cow::Borrowed(&sha_hex),
-        };
-        let mut cargo_cmd = Command::new("cargo");
-        cargo_cmd.arg("new")
-            .arg("--bin")
-            .args(&["--vcs", "none"])
-            .args(&["--name", &*package_name])
-            .args(&["--color", if isatty::stderr_isatty() { "always" } else { "never" }])
-            .current_dir(WORKSPACE_DIR.clone())
-            .arg(&sha_hex);
-
-        trace!("Running `cargo new` for the script crate";
-            "sha" => sha_hex, "name" => &*package_name, "cmd" => format!("{:?}", cargo_cmd));
-        let cargo_proc = cargo_cmd.spawn().unwrap_or_else(|err| {
-            error!("Failed to run cargo";
-                "cmd" => format!("{:?}", cargo_cmd), "error" => format!("{}", err));
-            exit(2);
-        });
-        let output = cargo_proc.wait_with_output().unwrap();
-        if !output.status.success() {
-            error!("cargo returned an error";
-                "cmd" => format!("{:?}", cargo_cmd), "status" => format!("{}", output.status));
-            io::stderr().write(&output.stderr).unwrap();
-            exit(2);
-        }
+    Ok(backend)
+}
 
-        // Extract the script's dependencies from the `extern crate` declarations
-        // and then add them to [dependencies] of the script's Cargo.toml.
-        lazy_static! {
-            // TODO: this is of course a fragile way to do this; use the `syn` crate
-            // to parse the script into Rust AST and pick the decls from that
-            static ref EXTERN_CRATE_RE: Regex = Regex::new(
-                r"extern\s+crate\s+(?P<name>\w+)\s*;"
-            ).unwrap();
-        }
-        let deps = {
-            let content = util::read_text_file(path).unwrap();
-            EXTERN_CRATE_RE.captures_iter(&content)
-                .map(|cap| cap.name("name").unwrap().to_owned()).collect::<Vec<_>>()
+/// Name of the file within script crate's directory that holds the SHA
+/// of the script code the crate has been last updated with.
+const SCRIPT_SHA_FILE: &'static str = ".script-sha1";
+
+
+/// Create a new script crate with given ID (which is also its directory name)
+/// and package name within given workspace.
+fn create_script_crate(workspace_dir: &Path, crate_id: &str, package_name: &str,
+                       options: &CargoOptions) -> Result<(), Error> {
+    // Run `cargo new --bin` to actually create the script crate.
+    // This is done outside of the workspace and the crate is only moved into it afterwards,
+    // so that other runrs processes building their scripts never see it half-initialized.
+    let staging_dir = STAGING_DIR.join(format!("{}.{}", crate_id, process::id()));
+    try!(fs::create_dir_all(&*STAGING_DIR).map_err(|e| Error::Io(STAGING_DIR.clone(), e)));
+    if staging_dir.exists() {
+        try!(fs::remove_dir_all(&staging_dir).map_err(|e| Error::Io(staging_dir.clone(), e)));
+    }
+    let mut cargo_cmd = Cargo::new("new").options(options)
+        .arg("--bin")
+        .arg("--vcs").arg("none")
+        .arg("--name").arg(package_name)
+        .arg("--color").arg(if isatty::stderr_isatty() { "always" } else { "never" })
+        .arg(&staging_dir)
+        .command();
+
+    trace!("Running `cargo new` for the script crate";
+        "crate_id" => crate_id, "name" => package_name, "cmd" => format!("{:?}", cargo_cmd));
+    let output = try!(cargo_cmd.output()
+        .map_err(|e| Error::Spawn("cargo new".into(), e)));
+    if !output.status.success() {
+        let _ = io::stderr().write_all(&output.stderr);
+        return Err(Error::Command("cargo new".into(), output.status));
+    }
+    let crate_dir = workspace_dir.join(crate_id);
+    fs::rename(&staging_dir, &crate_dir).map_err(|e| Error::Io(crate_dir, e))
+}
+
+/// Add the script crate path to [workspace.members] of the root Cargo.toml,
+/// unless it's there already.
+///
+/// Since this root is "virtual" (i.e. doesn't correspond to any crate on its own),
+/// this is the only way to define the workspace.
+fn add_workspace_member(workspace_dir: &Path, crate_id: &str) -> Result<(), Error> {
+    // Note that we do this only after the script crate has been created:
+    // other runrs processes may be building their scripts in the workspace in the meantime,
+    // and Cargo would fail for them if any of the members was missing.
+    trace!("Fixing root Cargo.toml to point to the script crate"; "crate_id" => crate_id);
+    update_workspace_members(workspace_dir, |members| members.push(crate_id))
+}
+
+/// Remove the paths of given script crates from [workspace.members] of the root Cargo.toml.
+/// The workspace should be locked (see `lock_workspace`).
+pub fn remove_workspace_members(workspace_dir: &Path, crate_ids: &[String]) -> Result<(), Error> {
+    trace!("Removing script crates from the root Cargo.toml";
+        "dir" => workspace_dir.display().to_string(), "count" => crate_ids.len());
+    update_workspace_members(workspace_dir, |members| members.retain(|m| {
+        m.as_str().is_none_or(|name| !crate_ids.iter().any(|id| id == name))
+    }))
+}
+
+/// Update [workspace.members] of the root Cargo.toml in given workspace directory.
+///
+/// The members are kept sorted and without duplicates, while any formatting or comments
+/// that the user may have added to the manifest are preserved.
+fn update_workspace_members<F>(workspace_dir: &Path, update: F) -> Result<(), Error>
+    where F: FnOnce(&mut toml_edit::Array)
+{
+    let root_cargo_toml = workspace_dir.join("Cargo.toml");
+    let content = try!(util::read_text_file(&root_cargo_toml)
+        .map_err(|e| Error::Io(root_cargo_toml.clone(), e)));
+
+    let mut root: toml_edit::DocumentMut = try!(content.parse().map_err(|e: toml_edit::TomlError| {
+        Error::Manifest(root_cargo_toml.clone(), e.to_string())
+    }));
+    {
+        let ws_members = match root.get_mut("workspace")
+                .and_then(|ws| ws.get_mut("members")).and_then(|m| m.as_array_mut()) {
+            Some(members) => members,
+            None => return Err(Error::Manifest(
+                root_cargo_toml, "workspace.members is not an array".into())),
         };
-        trace!("Extracted dependencies of the script";
-            "path" => path.display().to_string(), "deps" => format!("{:?}", deps));
-        // TODO: consider a way to specify deps versions (like a comment or something)
-
-        if !deps.is_empty() {
-            let content = util::read_text_file(&cargo_toml).unwrap();
-            let mut root: toml::Value = content.parse().unwrap();
-            {
-                // Ain't the toml crate's interface delightful?
-                let deps_value = root.lookup_mut("dependencies").unwrap();
-                let mut deps_map = deps_value.as_table().unwrap().to_owned();
-                for dep in deps {
-                    deps_map.insert(dep, toml::Value::String("*".into()));
-                }
-                *deps_value = toml::Value::Table(deps_map);
-            }
+        let member_names = |members: &toml_edit::Array| -> Vec<String> {
+            members.iter().map(|m| m.as_str().unwrap_or("").to_owned()).collect()
+        };
+        let old_members = member_names(ws_members);
 
-            let mut fp = fs::OpenOptions::new().write(true).open(&cargo_toml).unwrap();
-            write!(&mut fp, "{}", toml::encode_str(&root)).unwrap();
+        update(ws_members);
+        ws_members.sort_by_key(|m| m.as_str().map(str::to_owned));
+        let mut seen = HashSet::new();
+        ws_members.retain(|m| m.as_str().is_none_or(|name| seen.insert(name.to_owned())));
+        // Sorting moves the whitespace around the members along with them, so lay them out anew.
+        ws_members.fmt();
+
+        if member_names(ws_members) == old_members {
+            trace!("Workspace members are already up to date";
+                "dir" => workspace_dir.display().to_string());
+            return Ok(());
         }
+    }
 
-        debug!("Script crate initialized successfully";
-            "script" => path.display().to_string(), "sha" => sha_hex);
-    }
-
-    // Copy the script into the crate's directory as its main.rs.
-    // TODO: remove any shebangs
-    let main_rs = crate_dir.join("src").join("main.rs");
-    trace!("Copying script as src/main.rs";
-        "from" => path.display().to_string(), "to" => main_rs.display().to_string());
-    fs::copy(path, main_rs.clone()).unwrap_or_else(|err| {
-        error!("Failed to copy the script into crate src/";
-            "script" => path.display().to_string(), "target" => main_rs.display().to_string(),
-            "error" => format!("{}", err));
-       exit(exitcode::EX_OSFILE);
-    });
+    util::write_file_atomic(&root_cargo_toml, root.to_string().as_bytes())
+        .map_err(|e| Error::Io(root_cargo_toml, e))
+}
+
+/// Update the script crate's Cargo.toml (and possibly src/main.rs) to match the script.
+fn update_script_crate(crate_dir: &Path, package_name: &str, script: &Script,
+                       embedded_manifest: Option<toml::Table>, deps: Vec<Dependency>,
+                       edition: &str, config: &Config) -> Result<(), Error> {
+    // Scripts are compiled right from where they are, so that any relative paths they use
+    // (like in `mod` declarations or `include_str!`) work as expected.
+    // This isn't possible for scripts that don't come from a file, nor for those with frontmatter
+    // (which rustc doesn't understand), so those are written into the crate as src/main.rs.
+    // In the latter case, the script's modules are then referred to by their absolute paths.
+    let bin_path = match script.path {
+        Some(ref path) if script.frontmatter.is_none() => path.clone(),
+        _ => try!(write_script(&crate_dir.join("src").join("main.rs"), script)),
+    };
+    trace!("Script crate's binary source determined";
+        "script" => script.source.to_string(), "path" => bin_path.display().to_string());
+
+    // The script's dependencies are added to [dependencies] of its Cargo.toml,
+    // along with the script's own embedded manifest (which may specify them more precisely).
+    // The manifest is generated from scratch every time,
+    // so that it doesn't retain any dependencies the script no longer has.
+    // It's then applied onto the existing Cargo.toml, keeping the formatting of what's unchanged.
+    let cargo_toml = crate_dir.join("Cargo.toml");
+    let mut root = manifest::new(package_name, &bin_path);
+    manifest::set_edition(&mut root, edition);
+    if let Some(embedded) = embedded_manifest {
+        trace!("Merging the manifest embedded in the script";
+            "script" => script.source.to_string());
+        manifest::merge(&mut root, embedded);
+    }
+    manifest::add_inferred_deps(&mut root, deps, config);
+
+    // The manifest is replaced atomically, as other runrs processes may be building
+    // their scripts in the workspace (and thus reading it) at the same time.
+    let mut document = util::read_text_file(&cargo_toml).ok()
+        .and_then(|content| content.parse::<toml_edit::DocumentMut>().ok())
+        .unwrap_or_default();
+    manifest::update_document(&mut document, &root);
 
-    crate_dir
+    util::write_file_atomic(&cargo_toml, document.to_string().as_bytes())
+        .map_err(|e| Error::Io(cargo_toml, e))
+}
+
+/// Write the script's code into given file, e.g. src/main.rs of its crate
+/// (along with any other files needed to compile it from there, see `script_files`).
+/// Returns the path to that file.
+fn write_script(path: &Path, script: &Script) -> Result<PathBuf, Error> {
+    trace!("Writing script code into a file";
+        "from" => script.source.to_string(), "to" => path.display().to_string());
+    for (file_path, content) in script_files(script, path) {
+        let dir = file_path.parent().unwrap_or(Path::new(""));
+        try!(fs::create_dir_all(dir)
+            .and_then(|_| util::write_file_atomic(&file_path, content.as_bytes()))
+            .map_err(|e| Error::Io(file_path.clone(), e)));
+    }
+    Ok(path.to_owned())
+}
+
+/// Files to write for compiling the script from given source file rather than where it is,
+/// with their content: the script's code, and copies of its modules if needed.
+/// Paths to the script's modules are pinned, so that they're still found.
+fn script_files(script: &Script, source: &Path) -> Vec<(PathBuf, String)> {
+    let copies_dir = source.parent().unwrap_or(Path::new("")).join("modules");
+    let (code, copies) = match script.path {
+        Some(ref script_path) if !script.modules.is_empty() =>
+            modules::pin_paths(script_path, &script.code, &copies_dir),
+        _ => (script.code.clone(), vec![]),
+    };
+    iter::once((source.to_owned(), code))
+        .chain(copies.into_iter().map(|m| (m.path, m.code)))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use util;
+    use super::{add_workspace_member, remove_workspace_members};
+
+    #[test]
+    fn workspace_members_are_sorted_and_unique() {
+        let dir = env::temp_dir().join(format!("runrs-test-members-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cargo_toml = dir.join("Cargo.toml");
+        fs::write(&cargo_toml, "# Scripts' crates.\n[workspace]\nmembers = [\"c\", \"a\", \"c\"]\n")
+            .unwrap();
+
+        add_workspace_member(&dir, "b").unwrap();
+        add_workspace_member(&dir, "a").unwrap();
+        assert_eq!(util::read_text_file(&cargo_toml).unwrap(),
+                   "# Scripts' crates.\n[workspace]\nmembers = [\"a\", \"b\", \"c\"]\n");
+
+        remove_workspace_members(&dir, &["c".to_owned(), "x".to_owned()]).unwrap();
+        assert_eq!(util::read_text_file(&cargo_toml).unwrap(),
+                   "# Scripts' crates.\n[workspace]\nmembers = [\"a\", \"b\"]\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }